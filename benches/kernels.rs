@@ -0,0 +1,109 @@
+//! Times the vectorized 8-bit combine paths against the scalar per-pixel
+//! path they replace, on one thread so only the kernels are compared.
+//!
+//! Run with `cargo bench --bench kernels`.
+
+use image::{Rgba, RgbaImage};
+use rust_image_combiner::{
+    alternate_pixels, AlternatePixels, Blend, BlendMode, Combiner, CompositeOperator,
+};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
+const RUNS: u32 = 5;
+
+/// Hides a combiner's own `combine`, so the default per-pixel path is used.
+struct PerPixel<'a>(&'a dyn Combiner);
+
+impl Combiner for PerPixel<'_> {
+    fn combine_pixel(&self, pixels: &[Rgba<f32>]) -> Rgba<f32> {
+        self.0.combine_pixel(pixels)
+    }
+}
+
+/// An image of noise with partly transparent pixels, the same every run.
+fn noise(seed: u32) -> RgbaImage {
+    let mut state = seed;
+    RgbaImage::from_fn(WIDTH, HEIGHT, |_, _| {
+        // xorshift32
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        Rgba(state.to_le_bytes())
+    })
+}
+
+/// The fastest of a few runs of `f`.
+fn time<T>(mut f: impl FnMut() -> T) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(f());
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, scalar: Duration, simd: Duration) {
+    println!(
+        "{:<16} {:>10.2?} {:>10.2?} {:>7.1}x",
+        name,
+        scalar,
+        simd,
+        scalar.as_secs_f64() / simd.as_secs_f64()
+    );
+}
+
+fn main() {
+    let images = [noise(1), noise(2)];
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+
+    println!(
+        "{}x{}, two inputs, one thread, best of {}",
+        WIDTH, HEIGHT, RUNS
+    );
+    println!(
+        "{:<16} {:>10} {:>10} {:>8}",
+        "kernel", "scalar", "simd", "speedup"
+    );
+    pool.install(|| {
+        let mut output = RgbaImage::new(WIDTH, HEIGHT);
+        let vecs = [images[0].as_raw().as_slice(), images[1].as_raw().as_slice()];
+        report(
+            "alternate",
+            time(|| alternate_pixels(&vecs)),
+            time(|| AlternatePixels.combine(&images, &mut output)),
+        );
+
+        for operator in [CompositeOperator::SourceOver, CompositeOperator::Xor] {
+            report(
+                operator.name(),
+                time(|| PerPixel(&operator).combine(&images, &mut output)),
+                time(|| operator.combine(&images, &mut output)),
+            );
+        }
+
+        for mode in [
+            BlendMode::Multiply,
+            BlendMode::Screen,
+            BlendMode::Overlay,
+            BlendMode::Darken,
+            BlendMode::Lighten,
+            BlendMode::Difference,
+            BlendMode::SoftLight,
+        ] {
+            let blend = Blend::new(mode, 1.0);
+            report(
+                mode.name(),
+                time(|| PerPixel(&blend).combine(&images, &mut output)),
+                time(|| blend.combine(&images, &mut output)),
+            );
+        }
+    });
+}
@@ -1,22 +1,1293 @@
+use image::{io::Limits, ImageFormat};
+use rust_image_combiner::{
+    parse_color, parse_png_compression, parse_png_filter, Anaglyph, ChannelMap, Concat, Direction,
+    EncoderOptions, MatchSize, Montage, PackPreset, SizeStrategy, TargetSize, TextureMap,
+    STDIO_PATH,
+};
+use std::fmt;
+use std::str::FromStr;
 
-fn get_nth_arg(n: usize) -> String {
-    let mut args = std::env::args();
-    args.nth(n).unwrap_or_else(|| String::from(""))
+const USAGE: &str = "\
+Usage: rust-image-combiner [combine] [OPTIONS] <INPUT>... <OUTPUT>
+       rust-image-combiner pack [OPTIONS] <OUTPUT>
+       rust-image-combiner montage [OPTIONS] <INPUT>... <OUTPUT>
+       rust-image-combiner concat [OPTIONS] <INPUT>... <OUTPUT>
+       rust-image-combiner stereo [OPTIONS] <LEFT> <RIGHT> <OUTPUT>
+
+Run `rust-image-combiner <COMMAND> --help` for the options of a command.";
+
+/// The encoder options shared by every command that writes an image.
+macro_rules! encoder_help {
+    () => {
+        "\
+Encoder options:
+      --jpeg-quality <1..100>       JPEG quality [default: 75]
+      --png-compression <LEVEL>     PNG compression: default, fast, best,
+                                    huffman or rle [default: fast]
+      --png-filter <FILTER>         PNG filter: none, sub, up, avg, paeth or
+                                    adaptive [default: adaptive]
+
+JPEG chroma subsampling, WebP output and TIFF compression are unavailable:
+JPEG is always written at 4:4:4, TIFF uncompressed, and WebP not at all."
+    };
+}
+
+const COMBINE_HELP: &str = concat!(
+    "\
+Combine any number of images into one.
+
+Usage: rust-image-combiner [combine] [OPTIONS] <INPUT>... <OUTPUT>
+
+Arguments:
+  <INPUT>...  Images to combine, in order, or - for standard input
+  <OUTPUT>    Where to write the combined image, or - for standard output
+              (requires --format)
+
+The output keeps the highest bit depth among the inputs (8-bit, 16-bit or
+float) and drops color and alpha channels the result does not need, as far
+as the output format allows.
+
+Options:
+  -m, --mode <NAME>        Combine strategy [default: alternate]
+                           alternate, a blend mode (multiply, screen, overlay,
+                           soft-light, hard-light, color-dodge, color-burn,
+                           darken, lighten, difference, exclusion, add,
+                           subtract, divide) or a Porter-Duff operator
+                           (source-over, destination-over, source-in,
+                           source-out, source-atop, xor, clear)
+      --opacity <0..1>     Opacity of each layer for blend modes [default: 1]
+      --channels <MAP>     Build each channel from an input channel, e.g.
+                           r=1.r,g=2.g,b=2.l,a=255
+  -s, --size <SIZE>        Size every input is brought to: smallest, largest,
+                           first, second, input-N or WxH [default: smallest]
+      --fit <FIT>          How inputs are brought to that size: stretch,
+                           letterbox, crop or none [default: stretch]
+      --anchor <ANCHOR>    Where inputs sit when letterboxed, cropped or not
+                           resized: center, top, bottom-left, ... [default: center]
+      --background <COLOR> Fill for uncovered areas, e.g. #000000ff
+                           [default: transparent]
+      --filter <FILTER>    Resampling filter: nearest, triangle, catmull-rom,
+                           gaussian, lanczos3 or auto [default: nearest]
+      --linear             Shrink in linear light rather than on sRGB values
+      --max-size <WxH>     Refuse inputs wider or taller than this
+      --max-memory <MiB>   Refuse inputs that take more memory to decode, 0
+                           for no limit [default: 512]
+      --tiled              Combine a strip of rows at a time to bound memory;
+                           needs PNG or TIFF inputs of the same size and a
+                           PNG or TIFF output
+      --strip-rows <N>     Rows per strip, implies --tiled [default: 256]
+  -j, --threads <N>        Threads to decode and combine with, 0 for one per
+                           CPU core [default: 0]
+  -o, --output <OUTPUT>    Output path, instead of the last argument
+  -f, --format <FORMAT>    Output format, e.g. png or jpg [default: from the
+                           output extension]
+  -h, --help               Print help
+  -V, --version            Print version
+
+",
+    encoder_help!(),
+    "
+
+Exit status:
+  0  Success
+  2  Bad command line, unknown mode, channel map or preset
+  3  An input could not be read
+  4  The format of an input could not be determined
+  5  An input could not be decoded
+  6  The output format is unknown or cannot be written
+  7  The output could not be written
+  9  An image is too large to process"
+);
+
+const PACK_HELP: &str = concat!(
+    "\
+Pack two to four grayscale maps into the channels of one texture, at the
+highest bit depth among them.
+
+Usage: rust-image-combiner pack [OPTIONS] <OUTPUT>
+
+Arguments:
+  <OUTPUT>  Where to write the packed texture, or - for standard output
+            (requires --format)
+
+Options:
+  -p, --preset <NAME>       Channel layout: orm (Unreal) or mask (Unity HDRP)
+                            [default: orm]
+      --occlusion <PATH>    Ambient occlusion map, - for standard input
+                            [alias: --ao]
+      --roughness <PATH>    Roughness map
+      --metallic <PATH>     Metallic map
+      --height <PATH>       Height map, orm only
+      --max-size <WxH>      Refuse maps wider or taller than this
+      --max-memory <MiB>    Refuse maps that take more memory to decode, 0 for
+                            no limit [default: 512]
+  -j, --threads <N>         Threads to decode and pack with, 0 for one per CPU
+                            core [default: 0]
+  -o, --output <OUTPUT>     Output path, instead of the last argument
+  -f, --format <FORMAT>     Output format, e.g. png or jpg [default: from the
+                            output extension]
+  -h, --help                Print help
+  -V, --version             Print version
+
+",
+    encoder_help!()
+);
+
+const MONTAGE_HELP: &str = concat!(
+    "\
+Arrange images on a grid, row by row.
+
+Usage: rust-image-combiner montage [OPTIONS] <INPUT>... <OUTPUT>
+
+Arguments:
+  <INPUT>...  Images to arrange, in order, or - for standard input
+  <OUTPUT>    Where to write the montage, or - for standard output
+              (requires --format)
+
+The output keeps the highest bit depth among the inputs, as far as the output
+format allows.
+
+Options:
+      --columns <N>        Columns in the grid [default: from --rows, or as
+                           square as possible]
+      --rows <N>           Rows in the grid [default: as many as needed]
+      --cell <SIZE>        Cell size: smallest, largest, first, second,
+                           input-N or WxH [default: largest]
+      --fit <FIT>          How inputs are brought to the cell size: stretch,
+                           letterbox, crop or none [default: letterbox]
+      --anchor <ANCHOR>    Where inputs sit in their cell when letterboxed,
+                           cropped or not resized [default: center]
+      --background <COLOR> Fill for the spacing, margin, empty cells and
+                           uncovered parts of cells [default: transparent]
+      --spacing <PIXELS>   Space between cells [default: 0]
+      --margin <PIXELS>    Space around the grid [default: 0]
+      --filter <FILTER>    Resampling filter: nearest, triangle, catmull-rom,
+                           gaussian, lanczos3 or auto [default: nearest]
+      --linear             Shrink in linear light rather than on sRGB values
+      --max-size <WxH>     Refuse inputs wider or taller than this
+      --max-memory <MiB>   Refuse inputs that take more memory to decode, 0
+                           for no limit [default: 512]
+  -j, --threads <N>        Threads to decode and resize with, 0 for one per
+                           CPU core [default: 0]
+  -o, --output <OUTPUT>    Output path, instead of the last argument
+  -f, --format <FORMAT>    Output format, e.g. png or jpg [default: from the
+                           output extension]
+  -h, --help               Print help
+  -V, --version            Print version
+
+",
+    encoder_help!()
+);
+
+const CONCAT_HELP: &str = concat!(
+    "\
+Put images side by side or one over the other.
+
+Usage: rust-image-combiner concat [OPTIONS] <INPUT>... <OUTPUT>
+
+Arguments:
+  <INPUT>...  Images to put together, in order, or - for standard input
+  <OUTPUT>    Where to write the result, or - for standard output
+              (requires --format)
+
+The output keeps the highest bit depth among the inputs, as far as the output
+format allows.
+
+Options:
+  -d, --direction <DIR>     horizontal (side-by-side) or vertical (over-under)
+                            [default: horizontal]
+      --align <ALIGN>       Where narrower inputs sit: top, center or bottom
+                            side by side, left, center or right one over the
+                            other [default: center]
+      --match <SIZE>        Scale inputs, keeping their aspect ratio, to share
+                            a height (or width when vertical): smallest,
+                            largest, first, second, input-N or a number of
+                            pixels [default: keep sizes]
+      --separator <PIXELS>  Space between neighbouring inputs [default: 0]
+      --separator-color <COLOR>
+                            Fill for the separators [default: the padding]
+      --padding <COLOR>     Fill for areas no input covers, e.g. #000000ff
+                            [alias: --background] [default: transparent]
+      --filter <FILTER>     Resampling filter: nearest, triangle, catmull-rom,
+                            gaussian, lanczos3 or auto [default: nearest]
+      --linear              Shrink in linear light rather than on sRGB values
+      --max-size <WxH>      Refuse inputs wider or taller than this
+      --max-memory <MiB>    Refuse inputs that take more memory to decode, 0
+                            for no limit [default: 512]
+  -j, --threads <N>         Threads to decode and resize with, 0 for one per
+                            CPU core [default: 0]
+  -o, --output <OUTPUT>     Output path, instead of the last argument
+  -f, --format <FORMAT>     Output format, e.g. png or jpg [default: from the
+                            output extension]
+  -h, --help                Print help
+  -V, --version             Print version
+
+",
+    encoder_help!()
+);
+
+const STEREO_HELP: &str = concat!(
+    "\
+Mix a stereo pair into an anaglyph.
+
+Usage: rust-image-combiner stereo [OPTIONS] <LEFT> <RIGHT> <OUTPUT>
+
+Arguments:
+  <LEFT>      The left eye's view, or - for standard input
+  <RIGHT>     The right eye's view, stretched to the size of the left one if
+              they differ
+  <OUTPUT>    Where to write the anaglyph, or - for standard output
+              (requires --format)
+
+The output keeps the highest bit depth among the inputs, as far as the output
+format allows.
+
+Options:
+  -m, --method <METHOD>    true, gray, color, half-color or dubois
+                           [default: dubois]
+  -g, --glasses <COLORS>   Filters, left eye first: red-cyan, green-magenta or
+                           amber-blue [default: red-cyan]
+  -p, --parallax <PIXELS>  Move the right view this far right of the left
+                           one, or left if negative; only the overlap is kept
+                           [default: 0]
+      --linear             Mix in linear light rather than on sRGB values
+      --max-size <WxH>     Refuse inputs wider or taller than this
+      --max-memory <MiB>   Refuse inputs that take more memory to decode, 0
+                           for no limit [default: 512]
+  -j, --threads <N>        Threads to decode and mix with, 0 for one per CPU
+                           core [default: 0]
+  -o, --output <OUTPUT>    Output path, instead of the last argument
+  -f, --format <FORMAT>    Output format, e.g. png or jpg [default: from the
+                           output extension]
+  -h, --help               Print help
+  -V, --version            Print version
+
+",
+    encoder_help!()
+);
+
+const DEFAULT_STRIP_ROWS: u32 = 256;
+
+/// What the command line asked for.
+#[derive(Debug)]
+pub enum Command {
+    Combine(Args),
+    Pack(PackArgs),
+    Montage(MontageArgs),
+    Concat(ConcatArgs),
+    Stereo(StereoArgs),
+    Help(&'static str),
+    Version,
+}
+
+/// A command line that could not be understood.
+#[derive(Debug)]
+pub struct UsageError(pub String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error: {}\n\n{}", self.0, USAGE)
+    }
+}
+
+impl Command {
+    pub fn new() -> Result<Command, UsageError> {
+        Command::parse(std::env::args().skip(1))
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, UsageError> {
+        let mut args: Vec<String> = args.into_iter().collect();
+        let parse = match args.first().map(String::as_str) {
+            Some("pack") => PackArgs::parse,
+            Some("montage") => MontageArgs::parse,
+            Some("concat") => ConcatArgs::parse,
+            Some("stereo") => StereoArgs::parse,
+            Some("combine") => Args::parse,
+            Some("help") => {
+                return match args.get(1).map(String::as_str) {
+                    None | Some("combine") => Ok(Command::Help(COMBINE_HELP)),
+                    Some("pack") => Ok(Command::Help(PACK_HELP)),
+                    Some("montage") => Ok(Command::Help(MONTAGE_HELP)),
+                    Some("concat") => Ok(Command::Help(CONCAT_HELP)),
+                    Some("stereo") => Ok(Command::Help(STEREO_HELP)),
+                    Some(command) => Err(UsageError(format!("unknown command `{}`", command))),
+                };
+            }
+            _ => return Args::parse(args),
+        };
+        args.remove(0);
+        parse(args)
+    }
+}
+
+/// One token of the command line: either `--name`, `--name=value`, `-n`,
+/// `-nvalue` or a positional argument.
+enum Token {
+    Option(String, Option<String>),
+    Positional(String),
+}
+
+/// Splits raw arguments into [`Token`]s and hands out option values.
+struct Tokens {
+    args: std::vec::IntoIter<String>,
+    only_positional: bool,
+}
+
+impl Tokens {
+    fn new(args: Vec<String>) -> Self {
+        Tokens {
+            args: args.into_iter(),
+            only_positional: false,
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let arg = self.args.next()?;
+        if self.only_positional || arg == "-" || !arg.starts_with('-') {
+            return Some(Token::Positional(arg));
+        }
+        if arg == "--" {
+            self.only_positional = true;
+            return self.next();
+        }
+        if arg.starts_with("--") {
+            return match arg.split_once('=') {
+                Some((name, value)) => {
+                    Some(Token::Option(name.to_string(), Some(value.to_string())))
+                }
+                None => Some(Token::Option(arg, None)),
+            };
+        }
+
+        // A short option, possibly with its value attached as in `-j4`.
+        let split = arg.char_indices().nth(2).map_or(arg.len(), |(i, _)| i);
+        let (name, value) = arg.split_at(split);
+        let value = value.strip_prefix('=').unwrap_or(value);
+        let value = (!value.is_empty()).then(|| value.to_string());
+        Some(Token::Option(name.to_string(), value))
+    }
+
+    /// Checks that the flag `name` was not given a value, as in `--tiled=4`.
+    fn flag(name: &str, inline: Option<String>) -> Result<(), UsageError> {
+        match inline {
+            Some(value) => Err(UsageError(format!(
+                "`{}` does not take a value, got `{}`",
+                name, value
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the value of option `name`, either inline or the next argument.
+    fn value(&mut self, name: &str, inline: Option<String>) -> Result<String, UsageError> {
+        inline
+            .or_else(|| self.args.next())
+            .ok_or_else(|| UsageError(format!("`{}` needs a value", name)))
+    }
+}
+
+/// The options every command takes, and its positional arguments.
+struct CommonArgs {
+    positional: Vec<String>,
+    output: Option<String>,
+    format: Option<ImageFormat>,
+    encoder: EncoderOptions,
+    limits: Limits,
+    /// Worker threads, 0 for one per core.
+    threads: usize,
+}
+
+/// The outcome of [`parse_common`].
+enum Parsed {
+    Args(CommonArgs),
+    /// Help or the version was asked for, so the rest does not matter.
+    Done(Command),
+}
+
+/// Parses the options every command shares, printing `help` for `--help`,
+/// and hands any other option to `option`, which returns whether it knew it.
+fn parse_common<F>(
+    args: Vec<String>,
+    help: &'static str,
+    mut option: F,
+) -> Result<Parsed, UsageError>
+where
+    F: FnMut(&str, Option<String>, &mut Tokens) -> Result<bool, UsageError>,
+{
+    let mut tokens = Tokens::new(args);
+    let mut common = CommonArgs {
+        positional: Vec::new(),
+        output: None,
+        format: None,
+        encoder: EncoderOptions::default(),
+        limits: Limits::default(),
+        threads: 0,
+    };
+
+    while let Some(token) = tokens.next() {
+        let (name, inline) = match token {
+            Token::Positional(arg) => {
+                common.positional.push(arg);
+                continue;
+            }
+            Token::Option(name, inline) => (name, inline),
+        };
+        match name.as_str() {
+            "-h" | "--help" => {
+                Tokens::flag(&name, inline)?;
+                return Ok(Parsed::Done(Command::Help(help)));
+            }
+            "-V" | "--version" => {
+                Tokens::flag(&name, inline)?;
+                return Ok(Parsed::Done(Command::Version));
+            }
+            "-o" | "--output" => common.output = Some(tokens.value(&name, inline)?),
+            "-f" | "--format" => {
+                common.format = Some(parse_format(&name, &tokens.value(&name, inline)?)?)
+            }
+            "-j" | "--threads" => {
+                common.threads = parse_value(&name, &tokens.value(&name, inline)?)?;
+            }
+            _ if parse_encoder_option(&name, inline.clone(), &mut tokens, &mut common.encoder)? => {
+            }
+            _ if parse_limit_option(&name, inline.clone(), &mut tokens, &mut common.limits)? => {}
+            _ if option(&name, inline, &mut tokens)? => {}
+            _ => return Err(unknown_option(&name)),
+        }
+    }
+    Ok(Parsed::Args(common))
+}
+
+impl CommonArgs {
+    /// Splits the positional arguments into inputs and an output, which is
+    /// `--output` if given and the last positional argument otherwise.
+    fn inputs_and_output(&mut self) -> Result<(Vec<String>, String), UsageError> {
+        let output = match self.output.take() {
+            Some(output) => output,
+            None => self
+                .positional
+                .pop()
+                .ok_or_else(|| UsageError(String::from("missing input and output images")))?,
+        };
+        check_stdin(&self.positional)?;
+        check_stdout(&output, self.format)?;
+        Ok((std::mem::take(&mut self.positional), output))
+    }
+}
+
+/// Parses the value of `--format`, an image file extension such as `png`.
+fn parse_format(name: &str, value: &str) -> Result<ImageFormat, UsageError> {
+    ImageFormat::from_extension(value)
+        .ok_or_else(|| UsageError(format!("`{}`: unknown image format `{}`", name, value)))
+}
+
+/// Handles the options in [`encoder_help`], returning whether `name` was one
+/// of them.
+fn parse_encoder_option(
+    name: &str,
+    inline: Option<String>,
+    tokens: &mut Tokens,
+    options: &mut EncoderOptions,
+) -> Result<bool, UsageError> {
+    let usage = |e: rust_image_combiner::ImageDataErrors| UsageError(format!("`{}`: {}", name, e));
+
+    match name {
+        "--jpeg-quality" => {
+            let value = tokens.value(name, inline)?;
+            options.jpeg_quality = match value.parse::<u8>() {
+                Ok(quality) if (1..=100).contains(&quality) => quality,
+                _ => {
+                    return Err(UsageError(format!(
+                        "`{}` must be a number from 1 to 100, got `{}`",
+                        name, value
+                    )))
+                }
+            };
+        }
+        "--png-compression" => {
+            options.png_compression =
+                parse_png_compression(&tokens.value(name, inline)?).map_err(usage)?
+        }
+        "--png-filter" => {
+            options.png_filter = parse_png_filter(&tokens.value(name, inline)?).map_err(usage)?
+        }
+        "--jpeg-subsampling" | "--webp-lossless" | "--tiff-compression" => {
+            return Err(UsageError(format!(
+                "`{}` is unavailable, the bundled encoder cannot honour it",
+                name
+            )))
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Handles `--max-size` and `--max-memory`, returning whether `name` was one
+/// of them.
+fn parse_limit_option(
+    name: &str,
+    inline: Option<String>,
+    tokens: &mut Tokens,
+    limits: &mut Limits,
+) -> Result<bool, UsageError> {
+    match name {
+        "--max-size" => {
+            let value = tokens.value(name, inline)?;
+            let (width, height) = value
+                .split_once('x')
+                .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                .ok_or_else(|| {
+                    UsageError(format!(
+                        "`{}` must look like 4096x4096, got `{}`",
+                        name, value
+                    ))
+                })?;
+            limits.max_image_width = Some(width);
+            limits.max_image_height = Some(height);
+        }
+        "--max-memory" => {
+            let value = tokens.value(name, inline)?;
+            limits.max_alloc = match value.parse::<u64>() {
+                Ok(0) => None,
+                Ok(mebibytes) => Some(mebibytes.saturating_mul(1024 * 1024)),
+                Err(_) => {
+                    return Err(UsageError(format!(
+                        "`{}` must be a number of MiB, got `{}`",
+                        name, value
+                    )))
+                }
+            };
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Handles the options that say how an input is brought to a size, returning
+/// whether `name` was one of them.
+fn parse_fit_option(
+    name: &str,
+    inline: Option<String>,
+    tokens: &mut Tokens,
+    size: &mut SizeStrategy,
+) -> Result<bool, UsageError> {
+    match name {
+        "--fit" => size.fit = parse_value(name, &tokens.value(name, inline)?)?,
+        "--filter" => size.filter = parse_value(name, &tokens.value(name, inline)?)?,
+        "--linear" => {
+            Tokens::flag(name, inline)?;
+            size.linear_light = true;
+        }
+        "--anchor" => size.anchor = parse_value(name, &tokens.value(name, inline)?)?,
+        "--background" => {
+            let value = tokens.value(name, inline)?;
+            size.background =
+                parse_color(&value).map_err(|e| UsageError(format!("`{}`: {}", name, e)))?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Parses the value of option `name` as a number of at least `min`.
+fn parse_count(name: &str, value: &str, min: u32) -> Result<u32, UsageError> {
+    match value.parse::<u32>() {
+        Ok(count) if count >= min => Ok(count),
+        _ if min > 0 => Err(UsageError(format!(
+            "`{}` must be a positive number, got `{}`",
+            name, value
+        ))),
+        _ => Err(UsageError(format!(
+            "`{}` must be a number, got `{}`",
+            name, value
+        ))),
+    }
 }
 
+/// Standard input can only be read once, so at most one input may be `-`.
+fn check_stdin<'a>(inputs: impl IntoIterator<Item = &'a String>) -> Result<(), UsageError> {
+    let from_stdin = inputs
+        .into_iter()
+        .filter(|path| *path == STDIO_PATH)
+        .count();
+    if from_stdin > 1 {
+        return Err(UsageError(String::from(
+            "only one input can be read from standard input",
+        )));
+    }
+    Ok(())
+}
+
+/// Standard output has no extension to infer the format from.
+fn check_stdout(output: &str, format: Option<ImageFormat>) -> Result<(), UsageError> {
+    if output == STDIO_PATH && format.is_none() {
+        return Err(UsageError(String::from(
+            "writing to standard output requires --format",
+        )));
+    }
+    Ok(())
+}
+
+fn unknown_option(name: &str) -> UsageError {
+    UsageError(format!("unknown option `{}`", name))
+}
+
+/// Parses the value of option `name`, reporting failures as usage errors.
+fn parse_value<T>(name: &str, value: &str) -> Result<T, UsageError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| UsageError(format!("`{}`: {}", name, e)))
+}
+
+/// Options of the `combine` command.
 #[derive(Debug)]
-pub struct Args{
-    pub image_1: String,
-    pub image_2: String,
+pub struct Args {
+    pub images: Vec<String>,
     pub output: String,
+    pub format: Option<ImageFormat>,
+    pub encoder: EncoderOptions,
+    pub mode: String,
+    pub opacity: f32,
+    pub channels: Option<ChannelMap>,
+    pub size: SizeStrategy,
+    pub limits: Limits,
+    /// Rows per strip when combining in strips.
+    pub tiled: Option<u32>,
+    /// Worker threads, 0 for one per core.
+    pub threads: usize,
 }
 
 impl Args {
-    pub fn new() -> Args {
-        Args {
-            image_1: get_nth_arg(1),
-            image_2: get_nth_arg(2),
-            output: get_nth_arg(3),
+    fn parse(args: Vec<String>) -> Result<Command, UsageError> {
+        let mut mode = None;
+        let mut opacity = 1.0;
+        let mut channels: Option<ChannelMap> = None;
+        let mut size = SizeStrategy::default();
+        let mut tiled = None;
+
+        let parsed = parse_common(args, COMBINE_HELP, |name, inline, tokens| {
+            match name {
+                "-m" | "--mode" => mode = Some(tokens.value(name, inline)?),
+                "--opacity" => {
+                    let value = tokens.value(name, inline)?;
+                    opacity = match value.parse::<f32>() {
+                        Ok(opacity) if (0.0..=1.0).contains(&opacity) => opacity,
+                        _ => {
+                            return Err(UsageError(format!(
+                                "`--opacity` must be a number from 0 to 1, got `{}`",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--channels" => {
+                    channels = Some(parse_value(name, &tokens.value(name, inline)?)?);
+                }
+                "-s" | "--size" => {
+                    size.target = parse_value(name, &tokens.value(name, inline)?)?;
+                }
+                "--tiled" => {
+                    Tokens::flag(name, inline)?;
+                    tiled = tiled.or(Some(DEFAULT_STRIP_ROWS));
+                }
+                "--strip-rows" => {
+                    tiled = Some(parse_count(name, &tokens.value(name, inline)?, 1)?);
+                }
+                _ => return parse_fit_option(name, inline, tokens, &mut size),
+            }
+            Ok(true)
+        })?;
+        let mut common = match parsed {
+            Parsed::Args(common) => common,
+            Parsed::Done(command) => return Ok(command),
+        };
+
+        let (images, output) = common.inputs_and_output()?;
+        if images.is_empty() {
+            return Err(UsageError(String::from("missing input images")));
+        }
+
+        let mode = match (mode, &channels) {
+            (Some(mode), Some(_)) if mode != "channels" => {
+                return Err(UsageError(format!(
+                    "`--channels` cannot be used with `--mode {}`",
+                    mode
+                )))
+            }
+            (Some(mode), _) => mode,
+            (None, Some(_)) => String::from("channels"),
+            (None, None) => String::from("alternate"),
+        };
+        if let Some(map) = &channels {
+            if map.inputs_needed() > images.len() {
+                return Err(UsageError(format!(
+                    "the channel map reads input {} but there is no input {}",
+                    map.inputs_needed(),
+                    map.inputs_needed()
+                )));
+            }
+        }
+
+        if tiled.is_some() && size != SizeStrategy::default() {
+            return Err(UsageError(String::from(
+                "`--tiled` does not resize, so it cannot be used with the size options",
+            )));
+        }
+
+        if let TargetSize::Input(index) = size.target {
+            if index >= images.len() {
+                return Err(UsageError(format!(
+                    "`--size` refers to input {} but there is no input {}",
+                    index + 1,
+                    index + 1
+                )));
+            }
+        }
+
+        Ok(Command::Combine(Args {
+            images,
+            output,
+            format: common.format,
+            encoder: common.encoder,
+            mode,
+            opacity,
+            channels,
+            size,
+            limits: common.limits,
+            tiled,
+            threads: common.threads,
+        }))
+    }
+}
+
+/// Options of the `pack` command.
+#[derive(Debug)]
+pub struct PackArgs {
+    pub preset: PackPreset,
+    pub occlusion: Option<String>,
+    pub roughness: Option<String>,
+    pub metallic: Option<String>,
+    pub height: Option<String>,
+    pub output: String,
+    pub format: Option<ImageFormat>,
+    pub encoder: EncoderOptions,
+    pub limits: Limits,
+    /// Worker threads, 0 for one per core.
+    pub threads: usize,
+}
+
+impl PackArgs {
+    fn parse(args: Vec<String>) -> Result<Command, UsageError> {
+        let mut preset = PackPreset::UnrealOrm;
+        let mut preset_name = String::from("orm");
+        let mut maps: [Option<String>; 4] = Default::default();
+
+        let parsed = parse_common(args, PACK_HELP, |name, inline, tokens| {
+            match name {
+                "-p" | "--preset" => {
+                    preset_name = tokens.value(name, inline)?;
+                    preset = parse_value(name, &preset_name)?;
+                }
+                "--occlusion" | "--ao" => maps[0] = Some(tokens.value(name, inline)?),
+                "--roughness" => maps[1] = Some(tokens.value(name, inline)?),
+                "--metallic" => maps[2] = Some(tokens.value(name, inline)?),
+                "--height" => maps[3] = Some(tokens.value(name, inline)?),
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        let mut common = match parsed {
+            Parsed::Args(common) => common,
+            Parsed::Done(command) => return Ok(command),
+        };
+
+        // The maps are all options, so the only positional argument is the
+        // output.
+        let output = match common.output.take() {
+            Some(output) => output,
+            None => common
+                .positional
+                .pop()
+                .ok_or_else(|| UsageError(String::from("missing output image")))?,
+        };
+        if let Some(arg) = common.positional.first() {
+            return Err(UsageError(format!("unexpected argument `{}`", arg)));
+        }
+        let used = preset.channels().map(|channel| channel.map);
+        let options = ["--occlusion", "--roughness", "--metallic", "--height"];
+        let kinds = [
+            TextureMap::Occlusion,
+            TextureMap::Roughness,
+            TextureMap::Metallic,
+            TextureMap::Height,
+        ];
+        for ((map, option), kind) in maps.iter().zip(options).zip(kinds) {
+            if map.is_some() && !used.contains(&Some(kind)) {
+                return Err(UsageError(format!(
+                    "`{}` is not used by the {} preset",
+                    option, preset_name
+                )));
+            }
         }
+        if maps.iter().flatten().count() < 2 {
+            return Err(UsageError(String::from(
+                "at least two of --occlusion, --roughness, --metallic or --height are required",
+            )));
+        }
+
+        check_stdin(maps.iter().flatten())?;
+        check_stdout(&output, common.format)?;
+
+        let [occlusion, roughness, metallic, height] = maps;
+        Ok(Command::Pack(PackArgs {
+            preset,
+            occlusion,
+            roughness,
+            metallic,
+            height,
+            output,
+            format: common.format,
+            encoder: common.encoder,
+            limits: common.limits,
+            threads: common.threads,
+        }))
     }
-}
\ No newline at end of file
+}
+
+/// Options of the `montage` command.
+#[derive(Debug)]
+pub struct MontageArgs {
+    pub images: Vec<String>,
+    pub output: String,
+    pub format: Option<ImageFormat>,
+    pub encoder: EncoderOptions,
+    pub montage: Montage,
+    pub limits: Limits,
+    /// Worker threads, 0 for one per core.
+    pub threads: usize,
+}
+
+impl MontageArgs {
+    fn parse(args: Vec<String>) -> Result<Command, UsageError> {
+        let mut montage = Montage::default();
+
+        let parsed = parse_common(args, MONTAGE_HELP, |name, inline, tokens| {
+            match name {
+                "--columns" => {
+                    montage.columns = Some(parse_count(name, &tokens.value(name, inline)?, 1)?);
+                }
+                "--rows" => {
+                    montage.rows = Some(parse_count(name, &tokens.value(name, inline)?, 1)?);
+                }
+                "--cell" => {
+                    montage.cell.target = parse_value(name, &tokens.value(name, inline)?)?;
+                }
+                "--spacing" => {
+                    montage.spacing = parse_count(name, &tokens.value(name, inline)?, 0)?;
+                }
+                "--margin" => {
+                    montage.margin = parse_count(name, &tokens.value(name, inline)?, 0)?;
+                }
+                _ => return parse_fit_option(name, inline, tokens, &mut montage.cell),
+            }
+            Ok(true)
+        })?;
+        let mut common = match parsed {
+            Parsed::Args(common) => common,
+            Parsed::Done(command) => return Ok(command),
+        };
+
+        let (images, output) = common.inputs_and_output()?;
+        if images.is_empty() {
+            return Err(UsageError(String::from("missing input images")));
+        }
+
+        if let Err(e) = montage.grid(images.len()) {
+            return Err(UsageError(e.to_string()));
+        }
+        if let TargetSize::Input(index) = montage.cell.target {
+            if index >= images.len() {
+                return Err(UsageError(format!(
+                    "`--cell` refers to input {} but there is no input {}",
+                    index + 1,
+                    index + 1
+                )));
+            }
+        }
+
+        Ok(Command::Montage(MontageArgs {
+            images,
+            output,
+            format: common.format,
+            encoder: common.encoder,
+            montage,
+            limits: common.limits,
+            threads: common.threads,
+        }))
+    }
+}
+
+/// Options of the `concat` command.
+#[derive(Debug)]
+pub struct ConcatArgs {
+    pub images: Vec<String>,
+    pub output: String,
+    pub format: Option<ImageFormat>,
+    pub encoder: EncoderOptions,
+    pub concat: Concat,
+    pub limits: Limits,
+    /// Worker threads, 0 for one per core.
+    pub threads: usize,
+}
+
+impl ConcatArgs {
+    fn parse(args: Vec<String>) -> Result<Command, UsageError> {
+        let mut concat = Concat::default();
+        let mut align = None;
+        let mut separator_color = None;
+
+        let parsed = parse_common(args, CONCAT_HELP, |name, inline, tokens| {
+            match name {
+                "-d" | "--direction" => {
+                    concat.direction = parse_value(name, &tokens.value(name, inline)?)?;
+                }
+                "--align" => {
+                    let value = tokens.value(name, inline)?;
+                    concat.align = parse_value(name, &value)?;
+                    align = Some(value);
+                }
+                "--match" => {
+                    concat.matching = Some(parse_value(name, &tokens.value(name, inline)?)?);
+                }
+                "--separator" => {
+                    concat.separator = parse_count(name, &tokens.value(name, inline)?, 0)?;
+                }
+                "--separator-color" => {
+                    let value = tokens.value(name, inline)?;
+                    separator_color = Some(
+                        parse_color(&value)
+                            .map_err(|e| UsageError(format!("`{}`: {}", name, e)))?,
+                    );
+                }
+                "--padding" | "--background" => {
+                    let value = tokens.value(name, inline)?;
+                    concat.padding = parse_color(&value)
+                        .map_err(|e| UsageError(format!("`{}`: {}", name, e)))?;
+                }
+                "--filter" => concat.filter = parse_value(name, &tokens.value(name, inline)?)?,
+                "--linear" => {
+                    Tokens::flag(name, inline)?;
+                    concat.linear_light = true;
+                }
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        let mut common = match parsed {
+            Parsed::Args(common) => common,
+            Parsed::Done(command) => return Ok(command),
+        };
+
+        let (images, output) = common.inputs_and_output()?;
+        if images.is_empty() {
+            return Err(UsageError(String::from("missing input images")));
+        }
+
+        concat.separator_color = separator_color.unwrap_or(concat.padding);
+        let (direction, across) = match concat.direction {
+            Direction::Horizontal => ("side by side", ["top", "bottom"]),
+            Direction::Vertical => ("one over the other", ["left", "right"]),
+        };
+        if let Some(align) = align {
+            if matches!(align.as_str(), "top" | "bottom" | "left" | "right")
+                && !across.contains(&align.as_str())
+            {
+                return Err(UsageError(format!(
+                    "`--align {}` does not apply to images {}, use {} or {}",
+                    align, direction, across[0], across[1]
+                )));
+            }
+        }
+        if let Some(MatchSize::Input(index)) = concat.matching {
+            if index >= images.len() {
+                return Err(UsageError(format!(
+                    "`--match` refers to input {} but there is no input {}",
+                    index + 1,
+                    index + 1
+                )));
+            }
+        }
+
+        Ok(Command::Concat(ConcatArgs {
+            images,
+            output,
+            format: common.format,
+            encoder: common.encoder,
+            concat,
+            limits: common.limits,
+            threads: common.threads,
+        }))
+    }
+}
+
+/// Options of the `stereo` command.
+#[derive(Debug)]
+pub struct StereoArgs {
+    pub left: String,
+    pub right: String,
+    pub output: String,
+    pub format: Option<ImageFormat>,
+    pub encoder: EncoderOptions,
+    pub anaglyph: Anaglyph,
+    /// Pixels the right view is moved right of the left one.
+    pub parallax: i32,
+    pub limits: Limits,
+    /// Worker threads, 0 for one per core.
+    pub threads: usize,
+}
+
+impl StereoArgs {
+    fn parse(args: Vec<String>) -> Result<Command, UsageError> {
+        let mut anaglyph = Anaglyph::default();
+        let mut parallax = 0;
+
+        let parsed = parse_common(args, STEREO_HELP, |name, inline, tokens| {
+            match name {
+                "-m" | "--method" => {
+                    anaglyph.method = parse_value(name, &tokens.value(name, inline)?)?;
+                }
+                "-g" | "--glasses" => {
+                    anaglyph.glasses = parse_value(name, &tokens.value(name, inline)?)?;
+                }
+                "-p" | "--parallax" => {
+                    parallax = parse_value(name, &tokens.value(name, inline)?)?;
+                }
+                "--linear" => {
+                    Tokens::flag(name, inline)?;
+                    anaglyph.linear_light = true;
+                }
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        let mut common = match parsed {
+            Parsed::Args(common) => common,
+            Parsed::Done(command) => return Ok(command),
+        };
+
+        let (views, output) = common.inputs_and_output()?;
+        let [left, right]: [String; 2] = views.try_into().map_err(|views: Vec<_>| {
+            UsageError(format!(
+                "expected a left and a right view, got {} images",
+                views.len()
+            ))
+        })?;
+
+        Ok(Command::Stereo(StereoArgs {
+            left,
+            right,
+            output,
+            format: common.format,
+            encoder: common.encoder,
+            anaglyph,
+            parallax,
+            limits: common.limits,
+            threads: common.threads,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_image_combiner::{AnaglyphMethod, Glasses};
+
+    fn parse(line: &str) -> Result<Command, UsageError> {
+        Command::parse(line.split_whitespace().map(String::from))
+    }
+
+    fn error(line: &str) -> String {
+        match parse(line) {
+            Err(UsageError(message)) => message,
+            Ok(command) => panic!("`{}` parsed as {:?}", line, command),
+        }
+    }
+
+    #[test]
+    fn help_names_a_command() {
+        let help = |line| match parse(line) {
+            Ok(Command::Help(help)) => help,
+            other => panic!("`{}` parsed as {:?}", line, other),
+        };
+        assert_eq!(help("help"), COMBINE_HELP);
+        assert_eq!(help("help combine"), COMBINE_HELP);
+        assert_eq!(help("help pack"), PACK_HELP);
+        assert_eq!(help("help montage"), MONTAGE_HELP);
+        assert_eq!(help("help concat"), CONCAT_HELP);
+        assert_eq!(help("help stereo"), STEREO_HELP);
+        assert_eq!(help("--help"), COMBINE_HELP);
+        assert_eq!(help("pack -h"), PACK_HELP);
+        assert_eq!(help("stereo a.png --help"), STEREO_HELP);
+        assert_eq!(error("help nothing"), "unknown command `nothing`");
+        assert!(matches!(parse("montage -V"), Ok(Command::Version)));
+    }
+
+    #[test]
+    fn combine() {
+        let args = match parse("-j4 -m multiply --opacity=0.5 a.png b.png -fjpg out") {
+            Ok(Command::Combine(args)) => args,
+            other => panic!("parsed as {:?}", other),
+        };
+        assert_eq!(args.images, ["a.png", "b.png"]);
+        assert_eq!(args.output, "out");
+        assert_eq!(args.format, Some(ImageFormat::Jpeg));
+        assert_eq!(args.mode, "multiply");
+        assert_eq!(args.opacity, 0.5);
+        assert_eq!(args.threads, 4);
+        assert_eq!(args.tiled, None);
+
+        let args = match parse("combine --strip-rows 16 a.png b.png -o out.png --max-size 8x9") {
+            Ok(Command::Combine(args)) => args,
+            other => panic!("parsed as {:?}", other),
+        };
+        assert_eq!(args.images, ["a.png", "b.png"]);
+        assert_eq!(args.output, "out.png");
+        assert_eq!(args.tiled, Some(16));
+        assert_eq!(args.limits.max_image_width, Some(8));
+        assert_eq!(args.limits.max_image_height, Some(9));
+        assert_eq!(args.mode, "alternate");
+
+        assert!(matches!(
+            parse("--channels r=1.r,g=2.g a.png b.png out.png"),
+            Ok(Command::Combine(Args { ref mode, .. })) if mode == "channels"
+        ));
+        assert_eq!(error("out.png"), "missing input images");
+        assert_eq!(error(""), "missing input and output images");
+        assert_eq!(error("--nope a.png out.png"), "unknown option `--nope`");
+        assert_eq!(error("-x4 a.png out.png"), "unknown option `-x`");
+        assert_eq!(error("a.png -m"), "`-m` needs a value");
+        assert_eq!(
+            error("--tiled=8 a.png out.png"),
+            "`--tiled` does not take a value, got `8`"
+        );
+        assert_eq!(
+            error("--help=no"),
+            "`--help` does not take a value, got `no`"
+        );
+        assert_eq!(
+            error("--webp-lossless a.png out.png"),
+            "`--webp-lossless` is unavailable, the bundled encoder cannot honour it"
+        );
+        assert_eq!(
+            error("- - out.png"),
+            "only one input can be read from standard input"
+        );
+        assert_eq!(
+            error("a.png -"),
+            "writing to standard output requires --format"
+        );
+        assert_eq!(
+            error("--tiled --size largest a.png out.png"),
+            "`--tiled` does not resize, so it cannot be used with the size options"
+        );
+    }
+
+    #[test]
+    fn pack() {
+        let args = match parse("pack -pmask --ao=ao.png --roughness r.png -j 2 out.png") {
+            Ok(Command::Pack(args)) => args,
+            other => panic!("parsed as {:?}", other),
+        };
+        assert_eq!(args.preset, PackPreset::UnityMaskMap);
+        assert_eq!(args.occlusion.as_deref(), Some("ao.png"));
+        assert_eq!(args.roughness.as_deref(), Some("r.png"));
+        assert_eq!(args.metallic, None);
+        assert_eq!(args.height, None);
+        assert_eq!(args.output, "out.png");
+        assert_eq!(args.threads, 2);
+
+        assert_eq!(
+            error("pack --ao ao.png out.png"),
+            "at least two of --occlusion, --roughness, --metallic or --height are required"
+        );
+        assert_eq!(
+            error("pack --ao ao.png --height h.png"),
+            "missing output image"
+        );
+        assert_eq!(
+            error("pack --ao ao.png --height h.png a.png b.png"),
+            "unexpected argument `a.png`"
+        );
+        assert_eq!(
+            error("pack -p mask --ao ao.png --metallic m.png --height h.png out.png"),
+            "`--height` is not used by the mask preset"
+        );
+        assert_eq!(
+            error("pack --ao - --height - out.png"),
+            "only one input can be read from standard input"
+        );
+    }
+
+    #[test]
+    fn montage() {
+        let args = match parse("montage --columns 3 --spacing=4 --fit crop a b c d out.png") {
+            Ok(Command::Montage(args)) => args,
+            other => panic!("parsed as {:?}", other),
+        };
+        assert_eq!(args.images, ["a", "b", "c", "d"]);
+        assert_eq!(args.output, "out.png");
+        assert_eq!(args.montage.columns, Some(3));
+        assert_eq!(args.montage.spacing, 4);
+
+        assert!(error("montage --columns 0 a out.png").contains("positive"));
+        assert_eq!(
+            error("montage --cell input-3 a b out.png"),
+            "`--cell` refers to input 3 but there is no input 3"
+        );
+        assert_eq!(error("montage out.png"), "missing input images");
+    }
+
+    #[test]
+    fn concat() {
+        let args = match parse("concat -dvertical --align left --separator 2 a b out.png") {
+            Ok(Command::Concat(args)) => args,
+            other => panic!("parsed as {:?}", other),
+        };
+        assert_eq!(args.images, ["a", "b"]);
+        assert_eq!(args.concat.direction, Direction::Vertical);
+        assert_eq!(args.concat.separator, 2);
+        assert_eq!(args.concat.separator_color, args.concat.padding);
+
+        assert_eq!(
+            error("concat --align top -d vertical a b out.png"),
+            "`--align top` does not apply to images one over the other, use left or right"
+        );
+        assert_eq!(
+            error("concat --match input-3 a b out.png"),
+            "`--match` refers to input 3 but there is no input 3"
+        );
+    }
+
+    #[test]
+    fn stereo() {
+        let args = match parse("stereo -m color -g green-magenta -p-5 l.png r.png out.png") {
+            Ok(Command::Stereo(args)) => args,
+            other => panic!("parsed as {:?}", other),
+        };
+        assert_eq!(args.left, "l.png");
+        assert_eq!(args.right, "r.png");
+        assert_eq!(args.output, "out.png");
+        assert_eq!(args.anaglyph.method, AnaglyphMethod::Color);
+        assert_eq!(args.anaglyph.glasses, Glasses::GreenMagenta);
+        assert_eq!(args.parallax, -5);
+
+        assert_eq!(
+            error("stereo l.png out.png"),
+            "expected a left and a right view, got 1 images"
+        );
+    }
+}
@@ -0,0 +1,30 @@
+use crate::error::ImageDataErrors;
+use image::{ImageBuffer, Pixel, Primitive};
+use std::convert::TryFrom;
+
+/// Creates a blank `width` by `height` image to combine into.
+///
+/// Fails with [`ImageDataErrors::ImageTooLarge`], naming the image `name`,
+/// rather than aborting if the pixels do not fit in memory.
+pub(crate) fn new_image<P: Pixel>(
+    width: u32,
+    height: u32,
+    name: &str,
+) -> Result<ImageBuffer<P, Vec<P::Subpixel>>, ImageDataErrors> {
+    let mut data = Vec::new();
+    let len = buffer_size(width, height, P::CHANNEL_COUNT);
+    match len.and_then(|len| data.try_reserve_exact(len).ok().map(|_| len)) {
+        Some(len) => data.resize(len, P::Subpixel::DEFAULT_MIN_VALUE),
+        None => return Err(ImageDataErrors::ImageTooLarge(name.to_string())),
+    }
+    Ok(ImageBuffer::from_raw(width, height, data).unwrap())
+}
+
+/// The number of bytes `width * height` pixels of `bytes_per_pixel` take up,
+/// or `None` if that cannot be addressed on this platform.
+pub(crate) fn buffer_size(width: u32, height: u32, bytes_per_pixel: u8) -> Option<usize> {
+    let size = u64::from(width)
+        .checked_mul(u64::from(height))?
+        .checked_mul(u64::from(bytes_per_pixel))?;
+    usize::try_from(size).ok()
+}
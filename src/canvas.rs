@@ -0,0 +1,53 @@
+use crate::buffer;
+use crate::depth::{BitDepth, Channel};
+use crate::error::ImageDataErrors;
+use image::{imageops, DynamicImage, ImageBuffer, Pixel, Rgba};
+
+/// Creates a `width` by `height` RGBA image of `depth` filled with
+/// `background`, for laying other images out on.
+pub(crate) fn new_canvas(
+    width: u32,
+    height: u32,
+    background: Rgba<u8>,
+    depth: BitDepth,
+) -> Result<DynamicImage, ImageDataErrors> {
+    let name = format!("{}x{}", width, height);
+    Ok(match depth {
+        BitDepth::Eight => DynamicImage::ImageRgba8(filled(width, height, background, &name)?),
+        BitDepth::Sixteen => DynamicImage::ImageRgba16(filled(width, height, background, &name)?),
+        BitDepth::Float => DynamicImage::ImageRgba32F(filled(width, height, background, &name)?),
+    })
+}
+
+fn filled<P>(
+    width: u32,
+    height: u32,
+    background: Rgba<u8>,
+    name: &str,
+) -> Result<ImageBuffer<P, Vec<P::Subpixel>>, ImageDataErrors>
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let background = background
+        .0
+        .map(|value| P::Subpixel::from_unit(value.to_unit()));
+    let mut canvas: ImageBuffer<P, _> = buffer::new_image(width, height, name)?;
+    for pixel in canvas.pixels_mut() {
+        *pixel = *P::from_slice(&background);
+    }
+    Ok(canvas)
+}
+
+/// Copies `image` onto `canvas` with its top left corner at `(x, y)`,
+/// replacing what was there and converting it to the canvas's depth.
+pub(crate) fn paste(canvas: &mut DynamicImage, image: DynamicImage, x: i64, y: i64) {
+    match canvas {
+        DynamicImage::ImageRgba8(canvas) => imageops::replace(canvas, &image.into_rgba8(), x, y),
+        DynamicImage::ImageRgba16(canvas) => imageops::replace(canvas, &image.into_rgba16(), x, y),
+        DynamicImage::ImageRgba32F(canvas) => {
+            imageops::replace(canvas, &image.into_rgba32f(), x, y)
+        }
+        _ => unreachable!("canvases are always RGBA"),
+    }
+}
@@ -0,0 +1,302 @@
+use crate::combiner::{combine_buffers, Combiner};
+use crate::depth::{Channel, Rgba16Image};
+use crate::error::ImageDataErrors;
+use crate::layout::ColorLayout;
+use image::{ImageBuffer, Pixel, Rgba, Rgba32FImage, RgbaImage};
+use std::str::FromStr;
+
+/// Where a single output channel gets its value from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelSource {
+    /// A channel (0 = red .. 3 = alpha) of one of the inputs, counted from 0.
+    Channel { image: usize, channel: usize },
+    /// The Rec. 709 luminance of one of the inputs, counted from 0.
+    Luminance { image: usize },
+    /// The same value for every pixel, on the 8-bit scale.
+    Constant(u8),
+}
+
+impl ChannelSource {
+    /// Picks this source's value out of the input pixels, with channels in
+    /// `0.0..=1.0`. `pixels` must hold the input this source reads from.
+    pub fn sample(&self, pixels: &[Rgba<f32>]) -> f32 {
+        match *self {
+            ChannelSource::Channel { image, channel } => pixels[image][channel],
+            ChannelSource::Luminance { image } => {
+                let [r, g, b, _] = pixels[image].0;
+                0.2126 * r + 0.7152 * g + 0.0722 * b
+            }
+            ChannelSource::Constant(value) => value.to_unit(),
+        }
+    }
+
+    /// The input this source reads from, if it reads one at all.
+    pub fn image(&self) -> Option<usize> {
+        match *self {
+            ChannelSource::Channel { image, .. } | ChannelSource::Luminance { image } => {
+                Some(image)
+            }
+            ChannelSource::Constant(_) => None,
+        }
+    }
+}
+
+impl FromStr for ChannelSource {
+    type Err = ImageDataErrors;
+
+    /// Parses `2.g` (channel of an input, counted from 1), `1.l` (luminance of
+    /// an input) or `255` (a constant).
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = || ImageDataErrors::InvalidChannelMap(spec.to_string());
+
+        if let Ok(value) = spec.parse::<u8>() {
+            return Ok(ChannelSource::Constant(value));
+        }
+
+        let (image, channel) = spec.split_once('.').ok_or_else(invalid)?;
+        let image = match image.parse::<usize>() {
+            Ok(image) if image >= 1 => image - 1,
+            _ => return Err(invalid()),
+        };
+        match channel {
+            "l" | "luma" => Ok(ChannelSource::Luminance { image }),
+            _ => Ok(ChannelSource::Channel {
+                image,
+                channel: channel_index(channel).ok_or_else(invalid)?,
+            }),
+        }
+    }
+}
+
+fn channel_index(name: &str) -> Option<usize> {
+    match name {
+        "r" => Some(0),
+        "g" => Some(1),
+        "b" => Some(2),
+        "a" => Some(3),
+        _ => None,
+    }
+}
+
+/// Builds each output channel from any channel of any input, a constant or
+/// a luminance computation.
+///
+/// Channels that are not mentioned keep the [`AlternatePixels`] behaviour:
+/// red from the first image, green, blue and alpha from the second.
+///
+/// [`AlternatePixels`]: crate::AlternatePixels
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelMap {
+    pub sources: [ChannelSource; 4],
+}
+
+impl ChannelMap {
+    /// How many inputs the map reads from, i.e. the highest input used plus one.
+    pub fn inputs_needed(&self) -> usize {
+        self.sources
+            .iter()
+            .filter_map(|source| source.image())
+            .map(|image| image + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Combines `images` with [`Combiner::combine_pixel`], failing with
+    /// [`ImageDataErrors::TooFewInputs`] if the map reads an input that is
+    /// not there.
+    fn combine_checked<P>(
+        &self,
+        images: &[ImageBuffer<P, Vec<P::Subpixel>>],
+        output: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    ) -> Result<(), ImageDataErrors>
+    where
+        P: Pixel + Sync,
+        P::Subpixel: Channel,
+    {
+        if self.inputs_needed() > images.len() {
+            return Err(ImageDataErrors::TooFewInputs(
+                self.inputs_needed(),
+                images.len(),
+            ));
+        }
+        combine_buffers(self, images, output)
+    }
+}
+
+impl Default for ChannelMap {
+    fn default() -> Self {
+        ChannelMap {
+            sources: [
+                ChannelSource::Channel {
+                    image: 0,
+                    channel: 0,
+                },
+                ChannelSource::Channel {
+                    image: 1,
+                    channel: 1,
+                },
+                ChannelSource::Channel {
+                    image: 1,
+                    channel: 2,
+                },
+                ChannelSource::Channel {
+                    image: 1,
+                    channel: 3,
+                },
+            ],
+        }
+    }
+}
+
+impl FromStr for ChannelMap {
+    type Err = ImageDataErrors;
+
+    /// Parses a comma separated list like `r=1.r,g=2.g,b=2.b,a=1.a`.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut map = ChannelMap::default();
+
+        for assignment in spec.split(',').filter(|a| !a.is_empty()) {
+            let invalid = || ImageDataErrors::InvalidChannelMap(assignment.to_string());
+            let (channel, source) = assignment.split_once('=').ok_or_else(invalid)?;
+            let channel = channel_index(channel.trim()).ok_or_else(invalid)?;
+            map.sources[channel] = source.trim().parse()?;
+        }
+
+        Ok(map)
+    }
+}
+
+impl Combiner for ChannelMap {
+    fn combine_pixel(&self, pixels: &[Rgba<f32>]) -> Rgba<f32> {
+        let mut out = [0.0; 4];
+        for (value, source) in out.iter_mut().zip(&self.sources) {
+            *value = source.sample(pixels);
+        }
+        Rgba(out)
+    }
+
+    fn combine(&self, images: &[RgbaImage], output: &mut RgbaImage) -> Result<(), ImageDataErrors> {
+        self.combine_checked(images, output)
+    }
+
+    fn combine_16(
+        &self,
+        images: &[Rgba16Image],
+        output: &mut Rgba16Image,
+    ) -> Result<(), ImageDataErrors> {
+        self.combine_checked(images, output)
+    }
+
+    fn combine_32f(
+        &self,
+        images: &[Rgba32FImage],
+        output: &mut Rgba32FImage,
+    ) -> Result<(), ImageDataErrors> {
+        self.combine_checked(images, output)
+    }
+
+    /// The output is gray when red, green and blue all read the same value,
+    /// which any color channel of a gray input counts as.
+    fn output_layout(&self, inputs: &[ColorLayout]) -> ColorLayout {
+        let is_color = |image: usize| inputs.get(image).is_none_or(|input| input.color);
+        let [r, g, b] = [0, 1, 2].map(|c| match self.sources[c] {
+            ChannelSource::Channel { image, channel } if channel < 3 && !is_color(image) => {
+                ChannelSource::Luminance { image }
+            }
+            source => source,
+        });
+        let alpha = match self.sources[3] {
+            ChannelSource::Constant(value) => value != 255,
+            ChannelSource::Channel { image, channel: 3 } => {
+                inputs.get(image).is_none_or(|input| input.alpha)
+            }
+            _ => true,
+        };
+
+        ColorLayout {
+            color: r != g || g != b,
+            alpha,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{combine_images_at, BitDepth};
+    use image::DynamicImage;
+
+    #[test]
+    fn parses_sources() {
+        assert_eq!(
+            "2.g".parse::<ChannelSource>().unwrap(),
+            ChannelSource::Channel {
+                image: 1,
+                channel: 1
+            }
+        );
+        assert_eq!(
+            "1.luma".parse::<ChannelSource>().unwrap(),
+            ChannelSource::Luminance { image: 0 }
+        );
+        assert_eq!(
+            "128".parse::<ChannelSource>().unwrap(),
+            ChannelSource::Constant(128)
+        );
+        for bad in ["0.r", "1.x", "1.r.g", "256", "-1", "a.r", "1.", ""] {
+            assert!(bad.parse::<ChannelSource>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn parses_maps_over_the_default() {
+        let map: ChannelMap = "r=3.b, a=255".parse().unwrap();
+        assert_eq!(
+            map.sources,
+            [
+                ChannelSource::Channel {
+                    image: 2,
+                    channel: 2
+                },
+                ChannelMap::default().sources[1],
+                ChannelMap::default().sources[2],
+                ChannelSource::Constant(255),
+            ]
+        );
+        assert_eq!("".parse::<ChannelMap>().unwrap(), ChannelMap::default());
+        for bad in ["x=1.r", "r=1.q", "r", "r=1.r,g"] {
+            assert!(bad.parse::<ChannelMap>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn counts_the_inputs_read() {
+        assert_eq!(ChannelMap::default().inputs_needed(), 2);
+        let map: ChannelMap = "r=1.l,g=1.g,b=1.b,a=1.a".parse().unwrap();
+        assert_eq!(map.inputs_needed(), 1);
+        let map: ChannelMap = "r=0,g=0,b=0,a=255".parse().unwrap();
+        assert_eq!(map.inputs_needed(), 0);
+        let map: ChannelMap = "b=4.r".parse().unwrap();
+        assert_eq!(map.inputs_needed(), 4);
+    }
+
+    #[test]
+    fn samples_channels_luminance_and_constants() {
+        let pixels = [Rgba([1.0, 0.0, 0.0, 1.0]), Rgba([0.2, 0.4, 0.6, 0.8])];
+        let map: ChannelMap = "r=2.b,g=1.l,b=51,a=2.a".parse().unwrap();
+        let Rgba([r, g, b, a]) = map.combine_pixel(&pixels);
+        assert_eq!((r, a), (0.6, 0.8));
+        assert!((g - 0.2126).abs() < 1e-6);
+        assert!((b - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn refuses_too_few_inputs() {
+        let map: ChannelMap = "g=3.g".parse().unwrap();
+        let images = vec![DynamicImage::new_rgba8(2, 2), DynamicImage::new_rgba8(2, 2)];
+        for depth in [BitDepth::Eight, BitDepth::Sixteen, BitDepth::Float] {
+            let result = combine_images_at(images.clone(), &map, depth);
+            assert!(matches!(result, Err(ImageDataErrors::TooFewInputs(3, 2))));
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use crate::error::ImageDataErrors;
+use image::Rgba;
+
+/// Parses a color written as `#rrggbb`, `#rrggbbaa` (the `#` is optional) or
+/// one of the names `transparent`, `black` and `white`.
+pub fn parse_color(spec: &str) -> Result<Rgba<u8>, ImageDataErrors> {
+    let invalid = || ImageDataErrors::InvalidColor(spec.to_string());
+
+    match spec {
+        "transparent" => return Ok(Rgba([0, 0, 0, 0])),
+        "black" => return Ok(Rgba([0, 0, 0, 255])),
+        "white" => return Ok(Rgba([255, 255, 255, 255])),
+        _ => {}
+    }
+
+    let hex = spec.strip_prefix('#').unwrap_or(spec);
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut out = [255u8; 4];
+    for (i, value) in out.iter_mut().enumerate().take(hex.len() / 2) {
+        *value = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(Rgba(out))
+}
+
+/// Decodes an sRGB channel value in `0.0..=1.0` to linear light.
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encodes a linear light channel value in `0.0..=1.0` as sRGB.
+pub fn linear_to_srgb(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_functions_invert_each_other() {
+        for i in 0..=255 {
+            let value = i as f32 / 255.0;
+            let back = linear_to_srgb(srgb_to_linear(value));
+            assert!((back - value).abs() < 1e-5, "{}", value);
+        }
+        assert_eq!(srgb_to_linear(0.0), 0.0);
+        assert!((srgb_to_linear(1.0) - 1.0).abs() < 1e-6);
+        assert!((srgb_to_linear(0.5) - 0.214_041).abs() < 1e-5);
+        assert!((linear_to_srgb(0.5) - 0.735_357).abs() < 1e-5);
+        assert_eq!(linear_to_srgb(2.0), linear_to_srgb(1.0));
+    }
+}
@@ -0,0 +1,247 @@
+use crate::depth::{Channel, Rgba16Image};
+use crate::error::ImageDataErrors;
+use crate::layout::ColorLayout;
+use image::{ImageBuffer, Pixel, Rgba, Rgba32FImage, RgbaImage};
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// A strategy for mixing any number of images of the same size into one.
+///
+/// Implementors only have to provide [`Combiner::combine_pixel`], which works
+/// the same at every bit depth. Strategies that need to look at more than one
+/// pixel at a time, or that have a faster path for one depth, can override
+/// [`Combiner::combine`], [`Combiner::combine_16`] or [`Combiner::combine_32f`].
+pub trait Combiner: Send + Sync {
+    /// Combines the pixel at the same position in every image into an output
+    /// pixel. `pixels` holds one pixel per input, in input order, with every
+    /// channel in `0.0..=1.0` (float inputs may go beyond that).
+    fn combine_pixel(&self, pixels: &[Rgba<f32>]) -> Rgba<f32>;
+
+    /// Combines whole images of identical dimensions into `output`, an RGBA8
+    /// image of the same size. Fails with [`ImageDataErrors::MismatchedSizes`]
+    /// if an image is not the size of `output`.
+    fn combine(&self, images: &[RgbaImage], output: &mut RgbaImage) -> Result<(), ImageDataErrors> {
+        combine_buffers(self, images, output)
+    }
+
+    /// Combines whole images into an RGBA image with 16 bits per channel.
+    fn combine_16(
+        &self,
+        images: &[Rgba16Image],
+        output: &mut Rgba16Image,
+    ) -> Result<(), ImageDataErrors> {
+        combine_buffers(self, images, output)
+    }
+
+    /// Combines whole images into an RGBA image of floats.
+    fn combine_32f(
+        &self,
+        images: &[Rgba32FImage],
+        output: &mut Rgba32FImage,
+    ) -> Result<(), ImageDataErrors> {
+        combine_buffers(self, images, output)
+    }
+
+    /// Works out which channels the output needs given the layout of each
+    /// input. The default keeps color and alpha if any input has them.
+    fn output_layout(&self, inputs: &[ColorLayout]) -> ColorLayout {
+        ColorLayout::union_of(inputs)
+    }
+}
+
+/// Checks that every one of `images` has the dimensions of `output`.
+pub(crate) fn check_sizes<P: Pixel>(
+    images: &[ImageBuffer<P, Vec<P::Subpixel>>],
+    output: &ImageBuffer<P, Vec<P::Subpixel>>,
+) -> Result<(), ImageDataErrors> {
+    match images
+        .iter()
+        .position(|image| image.dimensions() != output.dimensions())
+    {
+        Some(i) => Err(ImageDataErrors::MismatchedSizes(i + 1)),
+        None => Ok(()),
+    }
+}
+
+/// Runs [`Combiner::combine_pixel`] over every pixel of `images`, writing
+/// the results to `output`.
+///
+/// Rows are combined in parallel on the rayon thread pool, each into its own
+/// slice of the output, so the result is the same whatever the thread count.
+pub(crate) fn combine_buffers<K, P>(
+    combiner: &K,
+    images: &[ImageBuffer<P, Vec<P::Subpixel>>],
+    output: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+) -> Result<(), ImageDataErrors>
+where
+    K: Combiner + ?Sized,
+    P: Pixel + Sync,
+    P::Subpixel: Channel,
+{
+    check_sizes(images, output)?;
+    let row_len = output.width() as usize * 4;
+    if row_len == 0 || images.is_empty() {
+        return Ok(());
+    }
+
+    output
+        .par_chunks_mut(row_len)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let start = y * row_len;
+            let mut pixels = Vec::with_capacity(images.len());
+            for (x, out) in row.chunks_exact_mut(4).enumerate() {
+                let i = start + x * 4;
+                pixels.clear();
+                pixels.extend(images.iter().map(|image| {
+                    let data = &image.as_raw()[i..i + 4];
+                    Rgba([0, 1, 2, 3].map(|c| data[c].to_unit()))
+                }));
+                let pixel = combiner.combine_pixel(&pixels);
+                for (channel, &value) in out.iter_mut().zip(pixel.0.iter()) {
+                    *channel = P::Subpixel::from_unit(value);
+                }
+            }
+        });
+    Ok(())
+}
+
+/// The original combine behaviour, generalised to any number of inputs:
+/// output channel `c` comes from input `c`, with the last input supplying
+/// every channel beyond that. With two inputs this is red from the first
+/// image and everything else from the second, a crude red-cyan anaglyph;
+/// [`crate::Anaglyph`] makes proper ones.
+pub struct AlternatePixels;
+
+impl Combiner for AlternatePixels {
+    fn combine_pixel(&self, pixels: &[Rgba<f32>]) -> Rgba<f32> {
+        let last = pixels.len() - 1;
+        Rgba([0, 1, 2, 3].map(|c| pixels[c.min(last)][c]))
+    }
+
+    fn combine(&self, images: &[RgbaImage], output: &mut RgbaImage) -> Result<(), ImageDataErrors> {
+        alternate_buffers(images, output, crate::simd::select_channels)
+    }
+
+    fn combine_16(
+        &self,
+        images: &[Rgba16Image],
+        output: &mut Rgba16Image,
+    ) -> Result<(), ImageDataErrors> {
+        alternate_buffers(images, output, crate::select_channels)
+    }
+
+    fn combine_32f(
+        &self,
+        images: &[Rgba32FImage],
+        output: &mut Rgba32FImage,
+    ) -> Result<(), ImageDataErrors> {
+        alternate_buffers(images, output, crate::select_channels)
+    }
+
+    /// Red and green come from different inputs as soon as there are two,
+    /// so the output is gray only for a single gray input.
+    fn output_layout(&self, inputs: &[ColorLayout]) -> ColorLayout {
+        let last = inputs.len().saturating_sub(1);
+        ColorLayout {
+            color: inputs.len() > 1 || inputs.iter().any(|input| input.color),
+            alpha: inputs.get(last.min(3)).is_some_and(|input| input.alpha),
+        }
+    }
+}
+
+fn alternate_buffers<P, F>(
+    images: &[ImageBuffer<P, Vec<P::Subpixel>>],
+    output: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    select: F,
+) -> Result<(), ImageDataErrors>
+where
+    P: Pixel,
+    P::Subpixel: Send + Sync,
+    F: Fn(&[&[P::Subpixel]], usize, &mut [P::Subpixel]) + Sync,
+{
+    check_sizes(images, output)?;
+    let vecs: Vec<&[P::Subpixel]> = images
+        .iter()
+        .map(|image| image.as_raw().as_slice())
+        .collect();
+    crate::alternate_pixels_into(&vecs, output, select)
+}
+
+/// A set of combiners looked up by name.
+///
+/// [`CombinerRegistry::default`] comes with every built-in strategy; use
+/// [`CombinerRegistry::register`] to add your own.
+pub struct CombinerRegistry {
+    combiners: HashMap<String, Box<dyn Combiner>>,
+}
+
+impl CombinerRegistry {
+    /// Creates a registry with no combiners in it.
+    pub fn new() -> Self {
+        CombinerRegistry {
+            combiners: HashMap::new(),
+        }
+    }
+
+    /// Adds `combiner` under `name`, replacing any combiner already there.
+    pub fn register<C: Combiner + 'static>(&mut self, name: &str, combiner: C) {
+        self.combiners.insert(name.to_string(), Box::new(combiner));
+    }
+
+    /// Looks up the combiner registered under `name`.
+    pub fn get(&self, name: &str) -> Option<&dyn Combiner> {
+        self.combiners.get(name).map(|combiner| combiner.as_ref())
+    }
+
+    /// Returns the registered names in alphabetical order.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.combiners.keys().map(|name| name.as_str()).collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+impl Default for CombinerRegistry {
+    fn default() -> Self {
+        let mut registry = CombinerRegistry::new();
+        registry.register("alternate", AlternatePixels);
+        crate::blend::register_blend_modes(&mut registry, 1.0);
+        crate::composite::register_composite_operators(&mut registry);
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{combine_images_at, BitDepth, Blend, BlendMode, ImageDataErrors};
+    use image::DynamicImage;
+
+    #[test]
+    fn inputs_of_different_sizes_are_refused() {
+        let images = vec![
+            DynamicImage::new_rgba8(4, 3),
+            DynamicImage::new_rgba8(4, 3),
+            DynamicImage::new_rgba8(3, 4),
+        ];
+        let result = combine_images_at(images, &AlternatePixels, BitDepth::Eight);
+        assert!(matches!(result, Err(ImageDataErrors::MismatchedSizes(3))));
+
+        let images = [RgbaImage::new(4, 3), RgbaImage::new(2, 3)];
+        let mut output = RgbaImage::new(4, 3);
+        let blend = Blend::new(BlendMode::Multiply, 1.0);
+        for combiner in [&blend as &dyn Combiner, &AlternatePixels] {
+            let result = combiner.combine(&images, &mut output);
+            assert!(matches!(result, Err(ImageDataErrors::MismatchedSizes(2))));
+        }
+
+        let images = [Rgba16Image::new(4, 3)];
+        let mut output = Rgba16Image::new(5, 3);
+        let result = blend.combine_16(&images, &mut output);
+        assert!(matches!(result, Err(ImageDataErrors::MismatchedSizes(1))));
+
+        let result = crate::alternate_pixels(&[&[0u8; 8][..], &[0; 4]]);
+        assert!(matches!(result, Err(ImageDataErrors::MismatchedSizes(2))));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::combiner::{Combiner, CombinerRegistry};
+use crate::error::ImageDataErrors;
+use crate::layout::ColorLayout;
+use crate::simd::{fold_images, Lanes, PixelKernel};
+use image::{Rgba, RgbaImage};
+
+/// The Porter-Duff compositing operators.
+///
+/// Each image is the source and everything before it the destination, so
+/// `SourceOver` draws the second image on top of the first, the third on top
+/// of that, and so on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompositeOperator {
+    SourceOver,
+    DestinationOver,
+    SourceIn,
+    SourceOut,
+    SourceAtop,
+    Xor,
+    Clear,
+}
+
+impl CompositeOperator {
+    /// Every operator, in the order they are listed in help output.
+    pub const ALL: [CompositeOperator; 7] = [
+        CompositeOperator::SourceOver,
+        CompositeOperator::DestinationOver,
+        CompositeOperator::SourceIn,
+        CompositeOperator::SourceOut,
+        CompositeOperator::SourceAtop,
+        CompositeOperator::Xor,
+        CompositeOperator::Clear,
+    ];
+
+    /// The name the operator is registered under, e.g. `"source-over"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            CompositeOperator::SourceOver => "source-over",
+            CompositeOperator::DestinationOver => "destination-over",
+            CompositeOperator::SourceIn => "source-in",
+            CompositeOperator::SourceOut => "source-out",
+            CompositeOperator::SourceAtop => "source-atop",
+            CompositeOperator::Xor => "xor",
+            CompositeOperator::Clear => "clear",
+        }
+    }
+
+    /// Returns the fractions of source and destination that make it into the
+    /// output, given the source and destination alpha.
+    pub fn factors(self, alpha_s: f32, alpha_d: f32) -> (f32, f32) {
+        self.factor_lanes(alpha_s, alpha_d)
+    }
+
+    /// [`CompositeOperator::factors`] for several pixels at once.
+    #[inline(always)]
+    fn factor_lanes<L: Lanes>(self, alpha_s: L, alpha_d: L) -> (L, L) {
+        let zero = L::splat(0.0);
+        let one = L::splat(1.0);
+        match self {
+            CompositeOperator::SourceOver => (one, one - alpha_s),
+            CompositeOperator::DestinationOver => (one - alpha_d, one),
+            CompositeOperator::SourceIn => (alpha_d, zero),
+            CompositeOperator::SourceOut => (one - alpha_d, zero),
+            CompositeOperator::SourceAtop => (alpha_d, one - alpha_s),
+            CompositeOperator::Xor => (one - alpha_d, one - alpha_s),
+            CompositeOperator::Clear => (zero, zero),
+        }
+    }
+
+    /// Composites the source `pixel_2` with the destination `pixel_1`.
+    /// Channels are in `0.0..=1.0`; colors above that are kept.
+    pub fn composite_pixel(self, pixel_1: Rgba<f32>, pixel_2: Rgba<f32>) -> Rgba<f32> {
+        Rgba(self.apply(pixel_1.0, pixel_2.0))
+    }
+}
+
+impl PixelKernel for CompositeOperator {
+    #[inline(always)]
+    fn apply<L: Lanes>(&self, pixel_1: [L; 4], pixel_2: [L; 4]) -> [L; 4] {
+        let zero = L::splat(0.0);
+        let alpha_d = pixel_1[3].clamp_unit();
+        let alpha_s = pixel_2[3].clamp_unit();
+        let (f_s, f_d) = self.factor_lanes(alpha_s, alpha_d);
+        let alpha_o = alpha_s * f_s + alpha_d * f_d;
+        let visible = alpha_o.gt(zero);
+
+        let mut out = [zero; 4];
+        for c in 0..3 {
+            let source = pixel_2[c] * alpha_s;
+            let destination = pixel_1[c] * alpha_d;
+            let premultiplied = source * f_s + destination * f_d;
+            out[c] = L::select(visible, (premultiplied / alpha_o).max(zero), zero);
+        }
+        out[3] = alpha_o;
+
+        out
+    }
+}
+
+impl Combiner for CompositeOperator {
+    /// Composites each image, as the source, onto the result of the ones
+    /// before it.
+    fn combine_pixel(&self, pixels: &[Rgba<f32>]) -> Rgba<f32> {
+        pixels[1..].iter().fold(pixels[0], |destination, source| {
+            self.composite_pixel(destination, *source)
+        })
+    }
+
+    fn combine(&self, images: &[RgbaImage], output: &mut RgbaImage) -> Result<(), ImageDataErrors> {
+        fold_images(self, images, output)
+    }
+
+    /// These operators can make opaque inputs transparent.
+    fn output_layout(&self, inputs: &[ColorLayout]) -> ColorLayout {
+        let layout = ColorLayout::union_of(inputs);
+        match self {
+            CompositeOperator::SourceOut | CompositeOperator::Xor | CompositeOperator::Clear
+                if inputs.len() > 1 =>
+            {
+                ColorLayout {
+                    alpha: true,
+                    ..layout
+                }
+            }
+            _ => layout,
+        }
+    }
+}
+
+/// Registers every [`CompositeOperator`] under its name.
+pub fn register_composite_operators(registry: &mut CombinerRegistry) {
+    for operator in CompositeOperator::ALL {
+        registry.register(operator.name(), operator);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operators_mix_partly_transparent_pixels() {
+        let destination = Rgba([1.0, 0.0, 0.0, 0.25]);
+        let source = Rgba([0.0, 0.0, 1.0, 0.5]);
+        let cases = [
+            (CompositeOperator::SourceOver, [0.2, 0.0, 0.8, 0.625]),
+            (CompositeOperator::DestinationOver, [0.4, 0.0, 0.6, 0.625]),
+            (CompositeOperator::SourceIn, [0.0, 0.0, 1.0, 0.125]),
+            (CompositeOperator::SourceOut, [0.0, 0.0, 1.0, 0.375]),
+            (CompositeOperator::SourceAtop, [0.5, 0.0, 0.5, 0.25]),
+            (CompositeOperator::Xor, [0.25, 0.0, 0.75, 0.5]),
+            (CompositeOperator::Clear, [0.0; 4]),
+        ];
+        for (operator, expected) in cases {
+            let actual = operator.composite_pixel(destination, source);
+            for (a, e) in actual.0.iter().zip(expected) {
+                assert!(
+                    (a - e).abs() < 1e-6,
+                    "{} gave {:?}, not {:?}",
+                    operator.name(),
+                    actual,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn later_images_are_composited_onto_earlier_ones() {
+        let pixels = [
+            Rgba([1.0, 0.0, 0.0, 1.0]),
+            Rgba([0.0, 1.0, 0.0, 0.5]),
+            Rgba([0.0, 0.0, 1.0, 0.5]),
+        ];
+        let Rgba([r, g, b, a]) = CompositeOperator::SourceOver.combine_pixel(&pixels);
+        assert_eq!([r, g, b, a], [0.25, 0.25, 0.5, 1.0]);
+    }
+}
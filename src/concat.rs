@@ -0,0 +1,379 @@
+use crate::canvas::{new_canvas, paste};
+use crate::depth::BitDepth;
+use crate::error::ImageDataErrors;
+use crate::layout::ColorLayout;
+use crate::resize::{ResizeFilter, SizeStrategy};
+use image::{DynamicImage, GenericImageView, Rgba};
+use rayon::prelude::*;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// Which way images are put next to each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Side by side, left to right.
+    Horizontal,
+    /// Over and under, top to bottom.
+    Vertical,
+}
+
+impl FromStr for Direction {
+    type Err = ImageDataErrors;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec {
+            "horizontal" | "h" | "side-by-side" => Ok(Direction::Horizontal),
+            "vertical" | "v" | "over-under" => Ok(Direction::Vertical),
+            _ => Err(ImageDataErrors::InvalidConcat(spec.to_string())),
+        }
+    }
+}
+
+/// Where an image sits across the direction of concatenation when it is
+/// narrower than the widest one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+    /// The top, or the left when stacked vertically.
+    Start,
+    Center,
+    /// The bottom, or the right when stacked vertically.
+    End,
+}
+
+impl Align {
+    /// The offset of `inner` pixels placed inside `outer`.
+    fn offset(self, outer: u32, inner: u32) -> u32 {
+        let free = outer.saturating_sub(inner);
+        match self {
+            Align::Start => 0,
+            Align::Center => free / 2,
+            Align::End => free,
+        }
+    }
+}
+
+impl FromStr for Align {
+    type Err = ImageDataErrors;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec {
+            "start" | "top" | "left" => Ok(Align::Start),
+            "center" | "middle" => Ok(Align::Center),
+            "end" | "bottom" | "right" => Ok(Align::End),
+            _ => Err(ImageDataErrors::InvalidConcat(spec.to_string())),
+        }
+    }
+}
+
+/// The size images are scaled to across the direction of concatenation:
+/// the height when side by side, the width when stacked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchSize {
+    /// The smallest input's.
+    Smallest,
+    /// The largest input's.
+    Largest,
+    /// The input at this index's, counted from 0.
+    Input(usize),
+    /// This many pixels.
+    Exact(u32),
+}
+
+impl FromStr for MatchSize {
+    type Err = ImageDataErrors;
+
+    /// Parses `smallest`, `largest`, `first`, `second`, `input-N` (counted
+    /// from 1) or a number of pixels.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = || ImageDataErrors::InvalidConcat(spec.to_string());
+
+        match spec {
+            "smallest" => return Ok(MatchSize::Smallest),
+            "largest" => return Ok(MatchSize::Largest),
+            "first" => return Ok(MatchSize::Input(0)),
+            "second" => return Ok(MatchSize::Input(1)),
+            _ => {}
+        }
+
+        if let Some(index) = spec.strip_prefix("input-") {
+            return match index.parse::<usize>() {
+                Ok(index) if index >= 1 => Ok(MatchSize::Input(index - 1)),
+                _ => Err(invalid()),
+            };
+        }
+
+        match spec.parse::<u32>() {
+            Ok(pixels) if pixels > 0 => Ok(MatchSize::Exact(pixels)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Puts images next to each other in a row or a column.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Concat {
+    pub direction: Direction,
+    pub align: Align,
+    /// Scale every image, keeping its aspect ratio, so they all share this
+    /// height (or width when stacked). `None` keeps their sizes.
+    pub matching: Option<MatchSize>,
+    /// Pixels between neighbouring images.
+    pub separator: u32,
+    pub separator_color: Rgba<u8>,
+    /// Fills whatever the images do not cover.
+    pub padding: Rgba<u8>,
+    pub filter: ResizeFilter,
+    /// Shrink images in linear light instead of on sRGB encoded values.
+    pub linear_light: bool,
+}
+
+impl Default for Concat {
+    fn default() -> Self {
+        Concat {
+            direction: Direction::Horizontal,
+            align: Align::Center,
+            matching: None,
+            separator: 0,
+            separator_color: Rgba([0, 0, 0, 0]),
+            padding: Rgba([0, 0, 0, 0]),
+            filter: ResizeFilter::Nearest,
+            linear_light: false,
+        }
+    }
+}
+
+impl Concat {
+    /// Splits `(width, height)` into the size along the direction of
+    /// concatenation and the size across it.
+    fn split(&self, (width, height): (u32, u32)) -> (u32, u32) {
+        match self.direction {
+            Direction::Horizontal => (width, height),
+            Direction::Vertical => (height, width),
+        }
+    }
+
+    /// The inverse of [`Concat::split`].
+    fn join(&self, along: u32, across: u32) -> (u32, u32) {
+        match self.direction {
+            Direction::Horizontal => (along, across),
+            Direction::Vertical => (across, along),
+        }
+    }
+
+    /// The size every image is placed at, in input order.
+    pub fn placed_dimensions(
+        &self,
+        dimensions: &[(u32, u32)],
+    ) -> Result<Vec<(u32, u32)>, ImageDataErrors> {
+        let across: Vec<u32> = dimensions.iter().map(|&size| self.split(size).1).collect();
+        let target = match self.matching {
+            None => return Ok(dimensions.to_vec()),
+            Some(MatchSize::Smallest) => across.iter().copied().min(),
+            Some(MatchSize::Largest) => across.iter().copied().max(),
+            Some(MatchSize::Input(index)) => match across.get(index) {
+                Some(&size) => Some(size),
+                None => {
+                    return Err(ImageDataErrors::InvalidConcat(format!(
+                        "input-{}",
+                        index + 1
+                    )))
+                }
+            },
+            Some(MatchSize::Exact(pixels)) => Some(pixels),
+        };
+        let target = target.ok_or(ImageDataErrors::NoInputImages)?;
+
+        Ok(dimensions
+            .iter()
+            .map(|&size| {
+                let (along, across) = self.split(size);
+                let scaled = (f64::from(along) * f64::from(target) / f64::from(across)).round();
+                self.join((scaled as u32).max(1), target)
+            })
+            .collect())
+    }
+
+    /// The channels the result needs given the layout and size of each
+    /// input: the inputs', plus the separator's and padding's where they show.
+    pub fn output_layout(&self, inputs: &[ColorLayout], dimensions: &[(u32, u32)]) -> ColorLayout {
+        let mut layout = ColorLayout::union_of(inputs);
+        if self.separator > 0 && inputs.len() > 1 {
+            layout = layout.union(ColorLayout::of_pixel(self.separator_color));
+        }
+        let placed = self
+            .placed_dimensions(dimensions)
+            .unwrap_or_else(|_| dimensions.to_vec());
+        let mut across = placed.iter().map(|&size| self.split(size).1);
+        let first = across.next();
+        if across.any(|size| Some(size) != first) {
+            layout = layout.union(ColorLayout::of_pixel(self.padding));
+        }
+        layout
+    }
+}
+
+/// Puts `images` next to each other following `concat`, producing an RGBA
+/// image of the given depth.
+pub fn concatenate(
+    images: Vec<DynamicImage>,
+    concat: &Concat,
+    depth: BitDepth,
+) -> Result<DynamicImage, ImageDataErrors> {
+    if images.is_empty() {
+        return Err(ImageDataErrors::NoInputImages);
+    }
+
+    let dimensions: Vec<_> = images.iter().map(|image| image.dimensions()).collect();
+    let placed = concat.placed_dimensions(&dimensions)?;
+    let length = placed
+        .iter()
+        .map(|&size| u64::from(concat.split(size).0))
+        .sum::<u64>()
+        + (placed.len() as u64 - 1) * u64::from(concat.separator);
+    let breadth = placed
+        .iter()
+        .map(|&size| concat.split(size).1)
+        .max()
+        .unwrap_or(0);
+    let length = u32::try_from(length).map_err(|_| {
+        ImageDataErrors::ImageTooLarge(format!("{} images put together", placed.len()))
+    })?;
+    let (width, height) = concat.join(length, breadth);
+    let mut canvas = new_canvas(width, height, concat.padding, depth)?;
+
+    let resize = SizeStrategy {
+        filter: concat.filter,
+        linear_light: concat.linear_light,
+        ..SizeStrategy::default()
+    };
+    let images: Vec<_> = images
+        .into_par_iter()
+        .zip(placed.par_iter())
+        .map(|(image, &size)| {
+            if image.dimensions() == size {
+                image
+            } else {
+                resize.resize(&image, size)
+            }
+        })
+        .collect();
+
+    let mut along = 0;
+    for (index, image) in images.into_iter().enumerate() {
+        if index > 0 && concat.separator > 0 {
+            let (x, y) = concat.join(along, 0);
+            let (separator_width, separator_height) = concat.join(concat.separator, breadth);
+            let separator = new_canvas(
+                separator_width,
+                separator_height,
+                concat.separator_color,
+                depth,
+            )?;
+            paste(&mut canvas, separator, i64::from(x), i64::from(y));
+            along += concat.separator;
+        }
+
+        let (size_along, size_across) = concat.split(image.dimensions());
+        let (x, y) = concat.join(along, concat.align.offset(breadth, size_across));
+        paste(&mut canvas, image, i64::from(x), i64::from(y));
+        along += size_along;
+    }
+
+    Ok(canvas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+    const GRAY: Rgba<u8> = Rgba([128, 128, 128, 255]);
+
+    /// A 4x6 red image and a 2x2 blue one.
+    fn images() -> Vec<DynamicImage> {
+        vec![
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 6, RED)),
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, BLUE)),
+        ]
+    }
+
+    fn join(concat: Concat) -> RgbaImage {
+        let concat = Concat {
+            padding: GRAY,
+            ..concat
+        };
+        concatenate(images(), &concat, BitDepth::Eight)
+            .unwrap()
+            .into_rgba8()
+    }
+
+    #[test]
+    fn side_by_side_adds_widths_and_keeps_the_tallest_height() {
+        let joined = join(Concat {
+            separator: 1,
+            separator_color: Rgba([0, 255, 0, 255]),
+            ..Concat::default()
+        });
+        assert_eq!(joined.dimensions(), (4 + 1 + 2, 6));
+        assert_eq!(*joined.get_pixel(4, 5), Rgba([0, 255, 0, 255]));
+    }
+
+    #[test]
+    fn over_under_adds_heights_and_keeps_the_widest_width() {
+        let joined = join(Concat {
+            direction: Direction::Vertical,
+            ..Concat::default()
+        });
+        assert_eq!(joined.dimensions(), (4, 6 + 2));
+    }
+
+    #[test]
+    fn smaller_images_are_aligned_across_the_direction() {
+        for (align, top) in [(Align::Start, 0), (Align::Center, 2), (Align::End, 4)] {
+            let joined = join(Concat {
+                align,
+                ..Concat::default()
+            });
+            let column: Vec<_> = (0..6).map(|y| *joined.get_pixel(4, y)).collect();
+            let expected: Vec<_> = (0..6)
+                .map(|y| {
+                    if (top..top + 2).contains(&y) {
+                        BLUE
+                    } else {
+                        GRAY
+                    }
+                })
+                .collect();
+            assert_eq!(column, expected, "{:?}", align);
+        }
+
+        let joined = join(Concat {
+            direction: Direction::Vertical,
+            align: Align::End,
+            ..Concat::default()
+        });
+        let row: Vec<_> = (0..4).map(|x| *joined.get_pixel(x, 6)).collect();
+        assert_eq!(row, [GRAY, GRAY, BLUE, BLUE]);
+    }
+
+    #[test]
+    fn matching_scales_to_a_shared_height() {
+        let concat = Concat {
+            matching: Some(MatchSize::Smallest),
+            ..Concat::default()
+        };
+        let placed = concat.placed_dimensions(&[(4, 6), (2, 2)]).unwrap();
+        assert_eq!(placed, [(1, 2), (2, 2)]);
+        assert_eq!(join(concat).dimensions(), (3, 2));
+
+        let concat = Concat {
+            direction: Direction::Vertical,
+            matching: Some(MatchSize::Exact(8)),
+            ..Concat::default()
+        };
+        let placed = concat.placed_dimensions(&[(4, 6), (2, 2)]).unwrap();
+        assert_eq!(placed, [(8, 12), (8, 8)]);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::layout::ColorLayout;
+use image::{DynamicImage, ImageBuffer, ImageFormat, Primitive, Rgba};
+
+/// An RGBA image with 16 bits per channel.
+pub type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+/// A channel type the combine pipeline can work in.
+///
+/// Combiners see every channel as an `f32` in `0.0..=1.0`; this converts to
+/// and from the stored representation.
+pub trait Channel: Primitive + Send + Sync + 'static {
+    fn to_unit(self) -> f32;
+
+    /// Converts back from `0.0..=1.0`, clamping integer types to their range.
+    fn from_unit(value: f32) -> Self;
+}
+
+impl Channel for u8 {
+    fn to_unit(self) -> f32 {
+        self as f32 / 255.0
+    }
+
+    fn from_unit(value: f32) -> Self {
+        (value.clamp(0.0, 1.0) * 255.0).round() as u8
+    }
+}
+
+impl Channel for u16 {
+    fn to_unit(self) -> f32 {
+        self as f32 / 65535.0
+    }
+
+    fn from_unit(value: f32) -> Self {
+        (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+    }
+}
+
+impl Channel for f32 {
+    fn to_unit(self) -> f32 {
+        self
+    }
+
+    /// Floats are passed through unclamped so HDR values survive.
+    fn from_unit(value: f32) -> Self {
+        value
+    }
+}
+
+/// The precision images are combined and written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+    Float,
+}
+
+impl BitDepth {
+    /// The depth of `image`'s channels.
+    pub fn of(image: &DynamicImage) -> Self {
+        let color = image.color();
+        match color.bytes_per_pixel() / color.channel_count() {
+            1 => BitDepth::Eight,
+            2 => BitDepth::Sixteen,
+            _ => BitDepth::Float,
+        }
+    }
+
+    /// The highest depth among `images`, or 8 bits if there are none.
+    pub fn highest(images: &[DynamicImage]) -> Self {
+        images
+            .iter()
+            .map(BitDepth::of)
+            .max()
+            .unwrap_or(BitDepth::Eight)
+    }
+
+    /// The depths the encoder for `format` can write images with the
+    /// channels of `layout` in, lowest first.
+    pub fn supported_by(format: ImageFormat, layout: ColorLayout) -> &'static [BitDepth] {
+        match format {
+            ImageFormat::Png | ImageFormat::Tiff => &[BitDepth::Eight, BitDepth::Sixteen],
+            // The PNM encoder writes 16 bits only for plain gray.
+            ImageFormat::Pnm if layout == ColorLayout::GRAY => {
+                &[BitDepth::Eight, BitDepth::Sixteen]
+            }
+            ImageFormat::Farbfeld => &[BitDepth::Sixteen],
+            ImageFormat::OpenExr => &[BitDepth::Float],
+            _ => &[BitDepth::Eight],
+        }
+    }
+
+    /// The depth to write `format` with the channels of `layout` in: this
+    /// depth if the encoder supports it, otherwise the highest supported depth
+    /// below it, otherwise the lowest supported depth.
+    pub fn for_format(self, format: ImageFormat, layout: ColorLayout) -> Self {
+        let supported = BitDepth::supported_by(format, layout);
+        supported
+            .iter()
+            .rev()
+            .copied()
+            .find(|&depth| depth <= self)
+            .unwrap_or(supported[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{combine_images_at, encode_image, AlternatePixels, EncoderOptions};
+    use image::{ImageBuffer, Luma, Rgb};
+
+    /// Runs `image` through the combine pipeline and writes it as PNM,
+    /// returning what was written and what decoding it gives back.
+    fn round_trip(image: DynamicImage) -> (DynamicImage, DynamicImage) {
+        let layout = ColorLayout::of(&image).for_format(ImageFormat::Pnm);
+        let depth = BitDepth::of(&image).for_format(ImageFormat::Pnm, layout);
+        let combined = combine_images_at(vec![image], &AlternatePixels, depth).unwrap();
+        let written = layout.apply(combined);
+        let encoded = encode_image(
+            &written,
+            "out.ppm",
+            ImageFormat::Pnm,
+            &EncoderOptions::default(),
+        )
+        .unwrap();
+        let decoded = image::load_from_memory_with_format(&encoded, ImageFormat::Pnm).unwrap();
+        (written, decoded)
+    }
+
+    #[test]
+    fn rgb16_is_written_to_pnm_at_eight_bits() {
+        let image = ImageBuffer::from_fn(5, 3, |x, y| {
+            Rgb([x as u16 * 13000, y as u16 * 30000, 65535 - x as u16 * 257])
+        });
+        let image = DynamicImage::ImageRgb16(image);
+        let (written, decoded) = round_trip(image.clone());
+
+        assert_eq!(BitDepth::of(&written), BitDepth::Eight);
+        assert_eq!(decoded.to_rgb8(), image.to_rgb8());
+    }
+
+    #[test]
+    fn gray16_is_written_to_pnm_at_sixteen_bits() {
+        let image = ImageBuffer::from_fn(5, 3, |x, y| Luma([x as u16 * 13000 + y as u16]));
+        let image = DynamicImage::ImageLuma16(image);
+        let (written, decoded) = round_trip(image.clone());
+
+        assert_eq!(BitDepth::of(&written), BitDepth::Sixteen);
+        assert_eq!(decoded.to_luma16(), image.to_luma16());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::error::ImageDataErrors;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::codecs::pnm::PnmEncoder;
+use image::{DynamicImage, ImageEncoder, ImageFormat};
+use std::io::Cursor;
+
+/// Per-format encoder settings. Settings for formats other than the one being
+/// written are ignored.
+///
+/// The bundled encoders leave little else to choose: JPEG is always written
+/// with 4:4:4 chroma, TIFF uncompressed, and WebP cannot be written at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncoderOptions {
+    /// JPEG quality from 1 to 100.
+    pub jpeg_quality: u8,
+    pub png_compression: CompressionType,
+    pub png_filter: FilterType,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        EncoderOptions {
+            jpeg_quality: 75,
+            png_compression: CompressionType::Fast,
+            png_filter: FilterType::Adaptive,
+        }
+    }
+}
+
+/// Parses a PNG compression level: `default`, `fast`, `best`, `huffman` or `rle`.
+pub fn parse_png_compression(spec: &str) -> Result<CompressionType, ImageDataErrors> {
+    match spec {
+        "default" => Ok(CompressionType::Default),
+        "fast" => Ok(CompressionType::Fast),
+        "best" => Ok(CompressionType::Best),
+        "huffman" => Ok(CompressionType::Huffman),
+        "rle" => Ok(CompressionType::Rle),
+        _ => Err(ImageDataErrors::InvalidEncoderOption(spec.to_string())),
+    }
+}
+
+/// Parses a PNG filter: `none`, `sub`, `up`, `avg`, `paeth` or `adaptive`.
+pub fn parse_png_filter(spec: &str) -> Result<FilterType, ImageDataErrors> {
+    match spec {
+        "none" => Ok(FilterType::NoFilter),
+        "sub" => Ok(FilterType::Sub),
+        "up" => Ok(FilterType::Up),
+        "avg" => Ok(FilterType::Avg),
+        "paeth" => Ok(FilterType::Paeth),
+        "adaptive" => Ok(FilterType::Adaptive),
+        _ => Err(ImageDataErrors::InvalidEncoderOption(spec.to_string())),
+    }
+}
+
+/// Encodes `image` in `format` using `options`, returning the encoded bytes.
+/// `name` is only used in error messages.
+pub fn encode_image(
+    image: &DynamicImage,
+    name: &str,
+    format: ImageFormat,
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, ImageDataErrors> {
+    let save_error = |e| ImageDataErrors::UnableToSaveImage(name.to_string(), e);
+    let (data, width, height, color_type) = (
+        image.as_bytes(),
+        image.width(),
+        image.height(),
+        image.color(),
+    );
+
+    let mut encoded = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => {
+            JpegEncoder::new_with_quality(&mut encoded, options.jpeg_quality.clamp(1, 100))
+                .encode(data, width, height, color_type)
+                .map_err(save_error)?;
+        }
+        ImageFormat::Png => {
+            PngEncoder::new_with_quality(&mut encoded, options.png_compression, options.png_filter)
+                .write_image(data, width, height, color_type)
+                .map_err(save_error)?;
+        }
+        ImageFormat::Pnm => {
+            let mut encoder = PnmEncoder::new(&mut encoded);
+            match image {
+                // The encoder only reads 16-bit samples as `u16`s, not as bytes.
+                DynamicImage::ImageLuma16(gray) => {
+                    encoder.encode(gray.as_raw().as_slice(), width, height, color_type)
+                }
+                _ => encoder.encode(data, width, height, color_type),
+            }
+            .map_err(save_error)?;
+        }
+        _ => {
+            image::write_buffer_with_format(&mut encoded, data, width, height, color_type, format)
+                .map_err(save_error)?;
+        }
+    }
+
+    Ok(encoded.into_inner())
+}
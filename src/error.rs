@@ -0,0 +1,186 @@
+use image::{ImageError, ImageFormat};
+use std::fmt;
+
+/// Everything that can go wrong while loading, combining or saving images.
+///
+/// Variants that concern a file carry its path as the first field.
+#[derive(Debug)]
+pub enum ImageDataErrors {
+    UnableToReadImageFromPath(String, std::io::Error),
+    /// The format of the file could not be determined.
+    UnableToFormatImage(String),
+    UnableToDecodeImage(String, ImageError),
+    UnableToSaveImage(String, ImageError),
+    /// The output format could not be told from the output path.
+    UnknownOutputFormat(String),
+    /// The output format is known but cannot be encoded.
+    UnsupportedOutputFormat(String, ImageFormat),
+    /// The encoder for the output format cannot honour the named setting.
+    UnsupportedEncoderOption(String, String),
+    UnknownCombineMode(String),
+    InvalidChannelMap(String),
+    UnknownPackPreset(String),
+    InvalidSizeStrategy(String),
+    InvalidColor(String),
+    InvalidEncoderOption(String),
+    NoInputImages,
+    /// The input at this position, counting from 1, is not the size of the
+    /// others.
+    MismatchedSizes(usize),
+    /// A combiner reads from this many inputs, but only so many were given.
+    TooFewInputs(usize, usize),
+    /// Only this many maps were given to pack, fewer than the two needed.
+    TooFewMaps(usize),
+    /// An image is larger than the decoder limits allow or than fits in
+    /// memory.
+    ImageTooLarge(String),
+    /// An input cannot be combined in strips, or the output cannot be
+    /// written in strips, for the given reason.
+    UnsupportedTiledInput(String, String),
+    /// A grid of this many columns by rows cannot hold this many images.
+    GridTooSmall(u32, u32, usize),
+    /// A concatenation direction, alignment or size to match is invalid.
+    InvalidConcat(String),
+    /// Anaglyph glasses or an anaglyph method is unknown.
+    InvalidAnaglyph(String),
+    /// A parallax of this many pixels leaves nothing of views this wide.
+    ParallaxTooLarge(i32, u32),
+}
+
+impl ImageDataErrors {
+    /// The process exit code the command line tool uses for this error.
+    ///
+    /// | Code | Meaning                                               |
+    /// |------|-------------------------------------------------------|
+    /// | 2    | Bad command line or configuration (mode, size, ...)   |
+    /// | 3    | An input could not be read                            |
+    /// | 4    | The format of an input could not be determined        |
+    /// | 5    | An input could not be decoded                         |
+    /// | 6    | The output format is unknown or cannot be written     |
+    /// | 7    | The output could not be written                       |
+    /// | 9    | An image is too large to process                      |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ImageDataErrors::UnknownCombineMode(_)
+            | ImageDataErrors::InvalidChannelMap(_)
+            | ImageDataErrors::UnknownPackPreset(_)
+            | ImageDataErrors::InvalidSizeStrategy(_)
+            | ImageDataErrors::InvalidColor(_)
+            | ImageDataErrors::InvalidEncoderOption(_)
+            | ImageDataErrors::UnsupportedTiledInput(..)
+            | ImageDataErrors::GridTooSmall(..)
+            | ImageDataErrors::InvalidConcat(_)
+            | ImageDataErrors::InvalidAnaglyph(_)
+            | ImageDataErrors::ParallaxTooLarge(..)
+            | ImageDataErrors::NoInputImages
+            | ImageDataErrors::MismatchedSizes(_)
+            | ImageDataErrors::TooFewInputs(..)
+            | ImageDataErrors::TooFewMaps(_) => 2,
+            ImageDataErrors::UnableToReadImageFromPath(..) => 3,
+            ImageDataErrors::UnableToFormatImage(_) => 4,
+            ImageDataErrors::UnableToDecodeImage(..) => 5,
+            ImageDataErrors::UnknownOutputFormat(_)
+            | ImageDataErrors::UnsupportedOutputFormat(..)
+            | ImageDataErrors::UnsupportedEncoderOption(..) => 6,
+            ImageDataErrors::UnableToSaveImage(..) => 7,
+            ImageDataErrors::ImageTooLarge(_) => 9,
+        }
+    }
+}
+
+impl fmt::Display for ImageDataErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageDataErrors::UnableToReadImageFromPath(path, _) => {
+                write!(f, "unable to read `{}`", path)
+            }
+            ImageDataErrors::UnableToFormatImage(path) => {
+                write!(f, "unable to tell what image format `{}` is in", path)
+            }
+            ImageDataErrors::UnableToDecodeImage(path, _) => {
+                write!(f, "unable to decode `{}`", path)
+            }
+            ImageDataErrors::UnableToSaveImage(path, _) => {
+                write!(f, "unable to save `{}`", path)
+            }
+            ImageDataErrors::UnknownOutputFormat(path) => write!(
+                f,
+                "unable to tell what image format to write `{}` in, use --format to pick one",
+                path
+            ),
+            ImageDataErrors::UnsupportedOutputFormat(path, format) => write!(
+                f,
+                "unable to write `{}`, writing {:?} images is not supported",
+                path, format
+            ),
+            ImageDataErrors::UnsupportedEncoderOption(path, option) => {
+                write!(f, "unable to write `{}`, {} is not supported", path, option)
+            }
+            ImageDataErrors::UnknownCombineMode(mode) => {
+                write!(f, "unknown combine mode `{}`", mode)
+            }
+            ImageDataErrors::InvalidChannelMap(spec) => {
+                write!(f, "invalid channel map `{}`", spec)
+            }
+            ImageDataErrors::UnknownPackPreset(preset) => {
+                write!(f, "unknown pack preset `{}`", preset)
+            }
+            ImageDataErrors::InvalidSizeStrategy(spec) => {
+                write!(f, "invalid size strategy `{}`", spec)
+            }
+            ImageDataErrors::InvalidColor(spec) => write!(f, "invalid color `{}`", spec),
+            ImageDataErrors::InvalidEncoderOption(spec) => {
+                write!(f, "invalid encoder option `{}`", spec)
+            }
+            ImageDataErrors::NoInputImages => write!(f, "no input images were given"),
+            ImageDataErrors::MismatchedSizes(position) => write!(
+                f,
+                "input {} is not the same size as the others, bring them to one size first",
+                position
+            ),
+            ImageDataErrors::TooFewInputs(needed, given) => write!(
+                f,
+                "the combiner reads input {} but only {} inputs were given",
+                needed, given
+            ),
+            ImageDataErrors::TooFewMaps(count) => write!(
+                f,
+                "at least two maps the preset uses are needed to pack, {} given",
+                count
+            ),
+            ImageDataErrors::ImageTooLarge(path) => {
+                write!(f, "`{}` is too large to process", path)
+            }
+            ImageDataErrors::UnsupportedTiledInput(path, reason) => {
+                write!(f, "unable to combine `{}` in strips, {}", path, reason)
+            }
+            ImageDataErrors::GridTooSmall(columns, rows, count) => write!(
+                f,
+                "a grid of {}x{} cells cannot hold {} images",
+                columns, rows, count
+            ),
+            ImageDataErrors::InvalidConcat(spec) => {
+                write!(f, "invalid concatenation setting `{}`", spec)
+            }
+            ImageDataErrors::InvalidAnaglyph(spec) => {
+                write!(f, "unknown anaglyph glasses or method `{}`", spec)
+            }
+            ImageDataErrors::ParallaxTooLarge(parallax, width) => write!(
+                f,
+                "a parallax of {} pixels leaves nothing of views {} pixels wide",
+                parallax, width
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageDataErrors {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImageDataErrors::UnableToReadImageFromPath(_, e) => Some(e),
+            ImageDataErrors::UnableToDecodeImage(_, e)
+            | ImageDataErrors::UnableToSaveImage(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use crate::depth::BitDepth;
+use image::{DynamicImage, ImageBuffer, ImageFormat, Pixel, Rgba};
+
+/// Which channels an image needs beyond a single gray one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorLayout {
+    /// Red, green and blue can differ.
+    pub color: bool,
+    /// Some pixels are not fully opaque.
+    pub alpha: bool,
+}
+
+impl ColorLayout {
+    pub const GRAY: ColorLayout = ColorLayout {
+        color: false,
+        alpha: false,
+    };
+    pub const RGBA: ColorLayout = ColorLayout {
+        color: true,
+        alpha: true,
+    };
+
+    /// The channels `image` is stored with.
+    pub fn of(image: &DynamicImage) -> Self {
+        let color = image.color();
+        ColorLayout {
+            color: color.has_color(),
+            alpha: color.has_alpha(),
+        }
+    }
+
+    /// The channels needed to store `pixel`.
+    pub fn of_pixel(pixel: Rgba<u8>) -> Self {
+        let [r, g, b, a] = pixel.0;
+        ColorLayout {
+            color: r != g || g != b,
+            alpha: a != 255,
+        }
+    }
+
+    /// The channels needed to store both layouts.
+    pub fn union(self, other: ColorLayout) -> Self {
+        ColorLayout {
+            color: self.color || other.color,
+            alpha: self.alpha || other.alpha,
+        }
+    }
+
+    /// The channels needed to store every one of `layouts`.
+    pub fn union_of(layouts: &[ColorLayout]) -> Self {
+        layouts
+            .iter()
+            .fold(ColorLayout::GRAY, |layout, other| layout.union(*other))
+    }
+
+    /// Adds whatever channels the encoder for `format` cannot do without, and
+    /// drops alpha where the format has no way to store it.
+    pub fn for_format(self, format: ImageFormat) -> Self {
+        match format {
+            // The ICO decoder only reads back RGBA.
+            ImageFormat::Farbfeld | ImageFormat::Ico => ColorLayout::RGBA,
+            ImageFormat::Gif | ImageFormat::OpenExr => ColorLayout {
+                color: true,
+                ..self
+            },
+            // The TIFF encoder writes gray, but not gray with alpha, and the BMP
+            // decoder reads gray with alpha back without it.
+            ImageFormat::Tiff | ImageFormat::Bmp if self.alpha => ColorLayout::RGBA,
+            // JPEG has no alpha, and the PNM decoder cannot read the PAM files
+            // the encoder writes for gray with alpha.
+            ImageFormat::Jpeg | ImageFormat::Pnm => ColorLayout {
+                alpha: false,
+                ..self
+            },
+            _ => self,
+        }
+    }
+
+    /// Drops the channels of an RGBA `image` this layout does not need. Gray
+    /// is taken from the red channel, so the image should really be gray.
+    /// There is no gray float type, so float images keep their color.
+    pub fn apply(self, image: DynamicImage) -> DynamicImage {
+        let color = self.color || BitDepth::of(&image) == BitDepth::Float;
+        let keep: &[usize] = match (color, self.alpha) {
+            (false, false) => &[0],
+            (false, true) => &[0, 3],
+            (true, false) => &[0, 1, 2],
+            (true, true) => return image,
+        };
+
+        match image {
+            DynamicImage::ImageRgba8(image) => match keep.len() {
+                1 => DynamicImage::ImageLuma8(strip(&image, keep)),
+                2 => DynamicImage::ImageLumaA8(strip(&image, keep)),
+                _ => DynamicImage::ImageRgb8(strip(&image, keep)),
+            },
+            DynamicImage::ImageRgba16(image) => match keep.len() {
+                1 => DynamicImage::ImageLuma16(strip(&image, keep)),
+                2 => DynamicImage::ImageLumaA16(strip(&image, keep)),
+                _ => DynamicImage::ImageRgb16(strip(&image, keep)),
+            },
+            DynamicImage::ImageRgba32F(image) => DynamicImage::ImageRgb32F(strip(&image, keep)),
+            image => image,
+        }
+    }
+}
+
+/// Copies the channels at `keep` out of every RGBA pixel of `image`.
+fn strip<P, Q>(
+    image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    keep: &[usize],
+) -> ImageBuffer<Q, Vec<Q::Subpixel>>
+where
+    P: Pixel,
+    Q: Pixel<Subpixel = P::Subpixel>,
+{
+    let data: Vec<_> = image
+        .as_raw()
+        .chunks_exact(4)
+        .flat_map(|pixel| keep.iter().map(move |&c| pixel[c]))
+        .collect();
+    ImageBuffer::from_raw(image.width(), image.height(), data).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{combine_images_at, encode_image, AlternatePixels, EncoderOptions};
+    use image::RgbaImage;
+
+    const LAYOUTS: [ColorLayout; 4] = [
+        ColorLayout::GRAY,
+        ColorLayout {
+            color: false,
+            alpha: true,
+        },
+        ColorLayout {
+            color: true,
+            alpha: false,
+        },
+        ColorLayout::RGBA,
+    ];
+
+    /// An RGBA image that needs exactly the channels of `layout`.
+    fn image_with(layout: ColorLayout) -> RgbaImage {
+        RgbaImage::from_fn(6, 4, |x, y| {
+            let gray = (x * 40 + y * 7) as u8;
+            let (g, b) = if layout.color {
+                (255 - gray, gray / 2)
+            } else {
+                (gray, gray)
+            };
+            let a = if layout.alpha {
+                64 + (y * 50) as u8
+            } else {
+                255
+            };
+            Rgba([gray, g, b, a])
+        })
+    }
+
+    /// Writes an image needing `layout` to `format` and decodes it again,
+    /// returning what was written and what came back.
+    fn round_trip(format: ImageFormat, layout: ColorLayout) -> (RgbaImage, DynamicImage) {
+        let image = DynamicImage::ImageRgba8(image_with(layout));
+        let layout = layout.for_format(format);
+        let depth = BitDepth::Eight.for_format(format, layout);
+        let combined = combine_images_at(vec![image], &AlternatePixels, depth).unwrap();
+        let written = layout.apply(combined);
+        let encoded = encode_image(&written, "out", format, &EncoderOptions::default())
+            .unwrap_or_else(|e| panic!("{:?} {:?}: {}", format, layout, e));
+        let decoded = image::load_from_memory_with_format(&encoded, format)
+            .unwrap_or_else(|e| panic!("{:?} {:?}: {}", format, layout, e));
+        (written.to_rgba8(), decoded)
+    }
+
+    #[test]
+    fn lossless_formats_read_back_what_was_written() {
+        let formats = [
+            ImageFormat::Png,
+            ImageFormat::Bmp,
+            ImageFormat::Ico,
+            ImageFormat::Pnm,
+            ImageFormat::Tiff,
+            ImageFormat::Tga,
+            ImageFormat::Farbfeld,
+        ];
+        for format in formats {
+            for layout in LAYOUTS {
+                let (written, decoded) = round_trip(format, layout);
+                assert_eq!(decoded.to_rgba8(), written, "{:?} {:?}", format, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn alpha_survives_where_the_format_can_store_it() {
+        let formats = [
+            ImageFormat::Png,
+            ImageFormat::Bmp,
+            ImageFormat::Ico,
+            ImageFormat::Tiff,
+            ImageFormat::Tga,
+            ImageFormat::Farbfeld,
+        ];
+        for format in formats {
+            for layout in LAYOUTS.into_iter().filter(|layout| layout.alpha) {
+                let (_, decoded) = round_trip(format, layout);
+                assert_eq!(
+                    decoded.to_rgba8(),
+                    image_with(layout),
+                    "{:?} {:?}",
+                    format,
+                    layout
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn jpeg_and_pnm_are_written_without_alpha() {
+        for format in [ImageFormat::Jpeg, ImageFormat::Pnm] {
+            for layout in LAYOUTS {
+                let (written, decoded) = round_trip(format, layout);
+                assert!(
+                    !ColorLayout::of(&decoded).alpha,
+                    "{:?} {:?}",
+                    format,
+                    layout
+                );
+                assert!(written.pixels().all(|pixel| pixel[3] == 255));
+            }
+        }
+    }
+}
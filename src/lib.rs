@@ -0,0 +1,366 @@
+//! Combine any number of images into one.
+//!
+//! The pipeline is: [`get_image_from_path`] to load each input,
+//! [`standardize_size_with`] to bring them all to the same dimensions,
+//! [`combine_images`] to mix their pixels and [`save_image`] to write the
+//! result out. How pixels are mixed is decided by a [`Combiner`], picked by
+//! name from a [`CombinerRegistry`].
+
+mod blend;
+mod buffer;
+mod canvas;
+mod channels;
+mod color;
+mod combiner;
+mod composite;
+mod concat;
+mod depth;
+mod encode;
+mod error;
+mod layout;
+mod montage;
+mod pack;
+mod resize;
+mod simd;
+mod stereo;
+mod tiled;
+
+pub use blend::{register_blend_modes, Blend, BlendMode};
+pub use channels::{ChannelMap, ChannelSource};
+pub use color::{linear_to_srgb, parse_color, srgb_to_linear};
+pub use combiner::{AlternatePixels, Combiner, CombinerRegistry};
+pub use composite::{register_composite_operators, CompositeOperator};
+pub use concat::{concatenate, Align, Concat, Direction, MatchSize};
+pub use depth::{BitDepth, Channel, Rgba16Image};
+pub use encode::{encode_image, parse_png_compression, parse_png_filter, EncoderOptions};
+pub use error::ImageDataErrors;
+pub use layout::ColorLayout;
+pub use montage::{montage, Montage};
+pub use pack::{pack_textures, PackChannel, PackPreset, TextureMap, TextureMaps};
+pub use resize::{
+    get_largest_dimensions, get_smallest_dimensions, Anchor, Fit, ResizeFilter, SizeStrategy,
+    TargetSize,
+};
+pub use stereo::{anaglyph, Anaglyph, AnaglyphMethod, Glasses};
+pub use tiled::combine_tiled;
+
+use image::{
+    io::{Limits, Reader},
+    DynamicImage, GenericImageView, ImageError, ImageFormat,
+};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Cursor, Read, Seek, Write};
+
+/// The path that stands for standard input or standard output.
+pub const STDIO_PATH: &str = "-";
+
+/// Loads and decodes the image at `path`, returning it with its format.
+///
+/// The format is detected from the file's contents, falling back to its
+/// extension when the contents are not recognised. A `path` of `-` reads the
+/// image from standard input. The decoder's default [`Limits`] apply.
+pub fn get_image_from_path(path: String) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
+    get_image_from_path_with(path, Limits::default())
+}
+
+/// Like [`get_image_from_path`], refusing images that exceed `limits` with
+/// [`ImageDataErrors::ImageTooLarge`].
+pub fn get_image_from_path_with(
+    path: String,
+    limits: Limits,
+) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
+    if path == STDIO_PATH {
+        return get_image_from_reader_with(std::io::stdin().lock(), path, limits);
+    }
+
+    match Reader::open(&path).and_then(|reader| reader.with_guessed_format()) {
+        Ok(reader) => decode(reader, path, limits),
+        Err(e) => Err(ImageDataErrors::UnableToReadImageFromPath(path, e)),
+    }
+}
+
+/// Reads an image from `reader`, detecting its format from its contents.
+/// `name` is only used in error messages.
+pub fn get_image_from_reader<R: Read>(
+    reader: R,
+    name: String,
+) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
+    get_image_from_reader_with(reader, name, Limits::default())
+}
+
+/// Like [`get_image_from_reader`], with control over the decoder limits.
+pub fn get_image_from_reader_with<R: Read>(
+    mut reader: R,
+    name: String,
+    limits: Limits,
+) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
+    let mut bytes = Vec::new();
+    if let Err(e) = reader.read_to_end(&mut bytes) {
+        return Err(ImageDataErrors::UnableToReadImageFromPath(name, e));
+    }
+
+    match Reader::new(Cursor::new(bytes)).with_guessed_format() {
+        Ok(reader) => decode(reader, name, limits),
+        Err(e) => Err(ImageDataErrors::UnableToReadImageFromPath(name, e)),
+    }
+}
+
+fn decode<R: BufRead + Seek>(
+    mut reader: Reader<R>,
+    name: String,
+    limits: Limits,
+) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
+    if let Some(format) = reader.format() {
+        reader.limits(limits);
+        match reader.decode() {
+            Ok(image) => Ok((image, format)),
+            Err(ImageError::Limits(_)) => Err(ImageDataErrors::ImageTooLarge(name)),
+            Err(e) => Err(ImageDataErrors::UnableToDecodeImage(name, e)),
+        }
+    } else {
+        Err(ImageDataErrors::UnableToFormatImage(name))
+    }
+}
+
+/// Returns the format the extension of `path` claims, if it disagrees with
+/// the `format` the file was actually found to be in.
+pub fn check_extension(path: &str, format: ImageFormat) -> Option<ImageFormat> {
+    match ImageFormat::from_path(path) {
+        Ok(claimed) if claimed != format => Some(claimed),
+        _ => None,
+    }
+}
+
+/// Resizes every image to the dimensions of the one with the fewest pixels.
+pub fn standardize_size(images: Vec<DynamicImage>) -> Result<Vec<DynamicImage>, ImageDataErrors> {
+    standardize_size_with(images, &SizeStrategy::default())
+}
+
+/// Brings every image to the same dimensions following `strategy`.
+pub fn standardize_size_with(
+    images: Vec<DynamicImage>,
+    strategy: &SizeStrategy,
+) -> Result<Vec<DynamicImage>, ImageDataErrors> {
+    let dimensions: Vec<_> = images.iter().map(|image| image.dimensions()).collect();
+    let target = strategy.target_dimensions(&dimensions)?;
+    // Resizing may produce float pixels, the widest kind.
+    if buffer::buffer_size(target.0, target.1, 16).is_none() {
+        return Err(ImageDataErrors::ImageTooLarge(format!(
+            "{}x{}",
+            target.0, target.1
+        )));
+    }
+
+    Ok(images
+        .into_par_iter()
+        .map(|image| strategy.apply(image, target))
+        .collect())
+}
+
+/// Combines equally sized images into a single RGBA image using `combiner`,
+/// at the highest bit depth among the inputs.
+///
+/// Fails with [`ImageDataErrors::ImageTooLarge`] if the output does not fit
+/// in memory.
+pub fn combine_images(
+    images: Vec<DynamicImage>,
+    combiner: &dyn Combiner,
+) -> Result<DynamicImage, ImageDataErrors> {
+    let depth = BitDepth::highest(&images);
+    combine_images_at(images, combiner, depth)
+}
+
+/// Combines equally sized images into a single RGBA image of the given depth.
+///
+/// The combiner writes straight into the output image. Fails with
+/// [`ImageDataErrors::MismatchedSizes`] if the images differ in size, see
+/// [`standardize_size_with`], and with [`ImageDataErrors::ImageTooLarge`] if
+/// the output does not fit in memory.
+pub fn combine_images_at(
+    images: Vec<DynamicImage>,
+    combiner: &dyn Combiner,
+    depth: BitDepth,
+) -> Result<DynamicImage, ImageDataErrors> {
+    let (width, height) = images.first().map_or((0, 0), |image| image.dimensions());
+    if let Some(i) = images
+        .iter()
+        .position(|image| image.dimensions() != (width, height))
+    {
+        return Err(ImageDataErrors::MismatchedSizes(i + 1));
+    }
+    let name = format!("{}x{}", width, height);
+
+    match depth {
+        BitDepth::Eight => {
+            let images: Vec<_> = images
+                .into_par_iter()
+                .map(|image| image.into_rgba8())
+                .collect();
+            let mut output = buffer::new_image(width, height, &name)?;
+            combiner.combine(&images, &mut output)?;
+            Ok(DynamicImage::ImageRgba8(output))
+        }
+        BitDepth::Sixteen => {
+            let images: Vec<_> = images
+                .into_par_iter()
+                .map(|image| image.into_rgba16())
+                .collect();
+            let mut output = buffer::new_image(width, height, &name)?;
+            combiner.combine_16(&images, &mut output)?;
+            Ok(DynamicImage::ImageRgba16(output))
+        }
+        BitDepth::Float => {
+            let images: Vec<_> = images
+                .into_par_iter()
+                .map(|image| image.into_rgba32f())
+                .collect();
+            let mut output = buffer::new_image(width, height, &name)?;
+            combiner.combine_32f(&images, &mut output)?;
+            Ok(DynamicImage::ImageRgba32F(output))
+        }
+    }
+}
+
+/// Takes channel `c` of every pixel from `vecs[c]`, using the last buffer for
+/// any channel past the number of buffers. Fails with
+/// [`ImageDataErrors::MismatchedSizes`] if the buffers differ in length.
+pub fn alternate_pixels<T: Copy + Send + Sync>(vecs: &[&[T]]) -> Result<Vec<T>, ImageDataErrors> {
+    let mut vec_out = match vecs.first() {
+        Some(first) => first.to_vec(),
+        None => return Ok(Vec::new()),
+    };
+    alternate_pixels_into(vecs, &mut vec_out, select_channels)?;
+    Ok(vec_out)
+}
+
+/// [`alternate_pixels`] into `out`, in parallel over fixed-size chunks. Each
+/// chunk is copied from the last buffer, then `select` fills in the channels
+/// taken from the others; it is given those buffers, where the chunk starts
+/// and the chunk itself. Every buffer must be as long as `out`.
+pub(crate) fn alternate_pixels_into<T, F>(
+    vecs: &[&[T]],
+    out: &mut [T],
+    select: F,
+) -> Result<(), ImageDataErrors>
+where
+    T: Copy + Send + Sync,
+    F: Fn(&[&[T]], usize, &mut [T]) + Sync,
+{
+    if let Some(i) = vecs.iter().position(|vec| vec.len() != out.len()) {
+        return Err(ImageDataErrors::MismatchedSizes(i + 1));
+    }
+    let last = match vecs.len().checked_sub(1) {
+        Some(last) => last,
+        None => return Ok(()),
+    };
+
+    // A whole number of pixels, so channel `c` sits at the same offsets in
+    // every chunk.
+    const CHUNK: usize = 4 * 16 * 1024;
+    let sources = &vecs[..last.min(4)];
+    out.par_chunks_mut(CHUNK)
+        .enumerate()
+        .for_each(|(n, chunk)| {
+            let start = n * CHUNK;
+            chunk.copy_from_slice(&vecs[last][start..start + chunk.len()]);
+            select(sources, start, chunk);
+        });
+    Ok(())
+}
+
+/// Copies channel `c` of every pixel in `out` from `sources[c]`, where `out`
+/// starts at element `start` of each source and at a pixel boundary.
+pub(crate) fn select_channels<T: Copy>(sources: &[&[T]], start: usize, out: &mut [T]) {
+    for (c, source) in sources.iter().enumerate() {
+        for i in (c..out.len()).step_by(4) {
+            out[i] = source[start + i];
+        }
+    }
+}
+
+/// Picks the format to write `path` in: `forced` if given, otherwise the one
+/// matching the file extension. Fails if that format cannot be encoded.
+pub fn get_output_format(
+    path: &str,
+    forced: Option<ImageFormat>,
+) -> Result<ImageFormat, ImageDataErrors> {
+    let format = match forced {
+        Some(format) => format,
+        None => ImageFormat::from_path(path)
+            .map_err(|_| ImageDataErrors::UnknownOutputFormat(path.to_string()))?,
+    };
+
+    if format.can_write() {
+        Ok(format)
+    } else {
+        Err(ImageDataErrors::UnsupportedOutputFormat(
+            path.to_string(),
+            format,
+        ))
+    }
+}
+
+/// Writes `image` to `path` in the given format. A path of `-` writes the
+/// image to standard output.
+pub fn save_image(
+    image: &DynamicImage,
+    path: &str,
+    format: ImageFormat,
+) -> Result<(), ImageDataErrors> {
+    save_image_with(image, path, format, &EncoderOptions::default())
+}
+
+/// Like [`save_image`], with control over the encoder settings.
+pub fn save_image_with(
+    image: &DynamicImage,
+    path: &str,
+    format: ImageFormat,
+    options: &EncoderOptions,
+) -> Result<(), ImageDataErrors> {
+    // Encode before touching the file so a failure does not leave it empty.
+    let encoded = encode_image(image, path, format, options)?;
+    if path == STDIO_PATH {
+        return write_encoded(path, std::io::stdout().lock(), &encoded);
+    }
+
+    let file = File::create(path).map_err(|e| {
+        ImageDataErrors::UnableToSaveImage(path.to_string(), ImageError::IoError(e))
+    })?;
+    write_encoded(path, BufWriter::new(file), &encoded)
+}
+
+/// Encodes `image` in the given format and writes it to `writer`. `name` is
+/// only used in error messages.
+pub fn write_image<W: Write>(
+    image: &DynamicImage,
+    name: &str,
+    writer: W,
+    format: ImageFormat,
+) -> Result<(), ImageDataErrors> {
+    write_image_with(image, name, writer, format, &EncoderOptions::default())
+}
+
+/// Like [`write_image`], with control over the encoder settings.
+pub fn write_image_with<W: Write>(
+    image: &DynamicImage,
+    name: &str,
+    writer: W,
+    format: ImageFormat,
+    options: &EncoderOptions,
+) -> Result<(), ImageDataErrors> {
+    // Some encoders need to seek, so encode into memory first.
+    let encoded = encode_image(image, name, format, options)?;
+    write_encoded(name, writer, &encoded)
+}
+
+fn write_encoded<W: Write>(
+    name: &str,
+    mut writer: W,
+    encoded: &[u8],
+) -> Result<(), ImageDataErrors> {
+    writer
+        .write_all(encoded)
+        .and_then(|_| writer.flush())
+        .map_err(|e| ImageDataErrors::UnableToSaveImage(name.to_string(), ImageError::IoError(e)))
+}
@@ -1,124 +1,230 @@
 mod args;
-use args::Args;
-use image::{
-    imageops::FilterType::Nearest, io::Reader, DynamicImage, GenericImageView, ImageError,
-    ImageFormat,
+use args::{Args, Command, ConcatArgs, MontageArgs, PackArgs, StereoArgs};
+use image::{io::Limits, DynamicImage, GenericImageView};
+use rayon::prelude::*;
+use rust_image_combiner::{
+    anaglyph, check_extension, combine_images_at, combine_tiled, concatenate,
+    get_image_from_path_with, get_output_format, pack_textures, register_blend_modes,
+    save_image_with, standardize_size_with, BitDepth, ColorLayout, Combiner, CombinerRegistry,
+    ImageDataErrors, TextureMaps,
 };
-use std::convert::TryInto;
-
-#[derive(Debug)]
-enum ImageDataErrors {
-    DifferentImageFormats,
-    BufferTooSmall,
-    UnableToReadImageFromPath(std::io::Error),
-    UnableToFormatImage(String),
-    UnableToDecodeImage(ImageError),
-    UnableToSaveImage(ImageError),
-}
-
-struct FloatingImage {
-    width: u32,
-    height: u32,
-    data: Vec<u8>,
-    name: String,
+use std::error::Error;
+use std::io::{ErrorKind, Write};
+
+fn main() {
+    let command = match Command::new() {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(command) {
+        eprintln!("error: {}", e);
+        let mut printed = e.to_string();
+        let mut source = e.source();
+        while let Some(cause) = source {
+            // Some errors repeat their cause in their own message.
+            let message = cause.to_string();
+            if !printed.ends_with(&message) {
+                eprintln!("  caused by: {}", message);
+            }
+            printed = message;
+            source = cause.source();
+        }
+        std::process::exit(e.exit_code());
+    }
 }
 
-impl FloatingImage {
-    fn new(width: u32, height: u32, name: String) -> Self {
-        let buffer_size = height * width * 4;
-        let buffer: Vec<u8> = Vec::with_capacity(buffer_size.try_into().unwrap());
-        FloatingImage {
-            width,
-            height,
-            data: buffer,
-            name,
+fn run(command: Command) -> Result<(), ImageDataErrors> {
+    match command {
+        Command::Combine(args) => combine(args),
+        Command::Pack(args) => pack(args),
+        Command::Montage(args) => montage(args),
+        Command::Concat(args) => concat(args),
+        Command::Stereo(args) => stereo(args),
+        Command::Help(help) => {
+            print(help);
+            Ok(())
+        }
+        Command::Version => {
+            print(concat!("rust-image-combiner ", env!("CARGO_PKG_VERSION")));
+            Ok(())
         }
     }
-    fn set_data(&mut self, data: Vec<u8>) -> Result<(), ImageDataErrors> {
-        if data.len() > self.data.capacity() {
-            return Err(ImageDataErrors::BufferTooSmall);
+}
+
+/// Prints `text` to standard output. Output piped into something that stops
+/// reading early, such as `head`, is not an error.
+fn print(text: &str) {
+    let mut stdout = std::io::stdout().lock();
+    match writeln!(stdout, "{}", text).and_then(|_| stdout.flush()) {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => {
+            eprintln!("error: unable to write to standard output");
+            eprintln!("  caused by: {}", e);
+            std::process::exit(7);
         }
-        self.data = data;
-        Ok(())
+        _ => {}
     }
 }
 
-fn main() -> Result<(), ImageDataErrors> {
-    let args = Args::new();
-    let (image_1, image_format_1) = get_image_from_path(args.image_1)?;
-    let (image_2, image_format_2) = get_image_from_path(args.image_2)?;
+/// Sizes the global rayon pool every parallel step runs on; 0 leaves the
+/// choice to rayon, one thread per core.
+fn use_threads(threads: usize) {
+    // This only fails if the pool was already built, which it cannot be
+    // before the first command runs.
+    let _ = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global();
+}
 
-    if image_format_1 != image_format_2 {
-        return Err(ImageDataErrors::DifferentImageFormats);
+/// Loads an input, warning when its contents and extension disagree.
+fn load(path: String, limits: &Limits) -> Result<DynamicImage, ImageDataErrors> {
+    let (image, format) = get_image_from_path_with(path.clone(), limits.clone())?;
+    if let Some(claimed) = check_extension(&path, format) {
+        eprintln!(
+            "warning: `{}` is named like a {:?} image but contains {:?}",
+            path, claimed, format
+        );
     }
+    Ok(image)
+}
 
-    let (image_1, image_2) = standardize_size(image_1, image_2);
-    let mut output = FloatingImage::new(image_1.width(), image_1.height(), args.output);
-    let combined_data = combine_images(image_1, image_2);
-    output.set_data(combined_data)?;
-
-    if let Err(e) = image::save_buffer_with_format(
-        output.name,
-        &output.data,
-        output.width,
-        output.height,
-        image::ColorType::Rgba8,
-        image_format_1,
-    ) {
-        Err(ImageDataErrors::UnableToSaveImage(e))
-    } else {
-        Ok(())
-    }
+/// Loads every input at once, but reports the first failure in input order.
+fn load_all(paths: Vec<String>, limits: &Limits) -> Result<Vec<DynamicImage>, ImageDataErrors> {
+    let images: Vec<_> = paths
+        .into_par_iter()
+        .map(|path| load(path, limits))
+        .collect();
+    images.into_iter().collect()
 }
 
-fn get_image_from_path(path: String) -> Result<(DynamicImage, ImageFormat), ImageDataErrors> {
-    match Reader::open(&path) {
-        Ok(reader) => {
-            if let Some(format) = reader.format() {
-                match reader.decode() {
-                    Ok(image) => Ok((image, format)),
-                    Err(e) => Err(ImageDataErrors::UnableToDecodeImage(e)),
-                }
-            } else {
-                return Err(ImageDataErrors::UnableToFormatImage(path));
-            }
-        }
-        Err(e) => Err(ImageDataErrors::UnableToReadImageFromPath(e)),
+fn combine(args: Args) -> Result<(), ImageDataErrors> {
+    use_threads(args.threads);
+    let mut registry = CombinerRegistry::default();
+    register_blend_modes(&mut registry, args.opacity);
+    if let Some(map) = args.channels {
+        registry.register("channels", map);
+    }
+    let combiner = registry
+        .get(&args.mode)
+        .ok_or_else(|| ImageDataErrors::UnknownCombineMode(args.mode.clone()))?;
+
+    let output_format = get_output_format(&args.output, args.format)?;
+    if let Some(strip_rows) = args.tiled {
+        return combine_tiled(
+            &args.images,
+            &args.output,
+            output_format,
+            combiner,
+            &args.encoder,
+            &args.limits,
+            strip_rows,
+        );
     }
-}
 
-fn get_smallest_dimensions(dim_1: (u32, u32), dim_2: (u32, u32)) -> (u32, u32) {
-    let pix_1 = dim_1.0 * dim_1.1;
-    let pix_2 = dim_2.0 * dim_2.1;
+    let images = load_all(args.images, &args.limits)?;
+    if images.is_empty() {
+        return Err(ImageDataErrors::NoInputImages);
+    }
 
-    return if pix_1 < pix_2 { dim_1 } else { dim_2 };
+    // Keep the most precise input's depth and only the channels the result
+    // needs, as far as the output format allows.
+    let layouts: Vec<_> = images
+        .iter()
+        .map(|image| args.size.output_layout(ColorLayout::of(image)))
+        .collect();
+    let layout = combiner.output_layout(&layouts).for_format(output_format);
+    let depth = BitDepth::highest(&images).for_format(output_format, layout);
+    let images = standardize_size_with(images, &args.size)?;
+    let combined = layout.apply(combine_images_at(images, combiner, depth)?);
+
+    save_image_with(&combined, &args.output, output_format, &args.encoder)
 }
 
-fn standardize_size(image_1: DynamicImage, image_2: DynamicImage) -> (DynamicImage, DynamicImage) {
-    let (width, height) = get_smallest_dimensions(image_1.dimensions(), image_2.dimensions());
-    let image_1 = image_1.resize_exact(width, height, Nearest);
-    let image_2 = image_2.resize_exact(width, height, Nearest);
-
-    return (image_1, image_2);
+fn pack(args: PackArgs) -> Result<(), ImageDataErrors> {
+    use_threads(args.threads);
+    let output_format = get_output_format(&args.output, args.format)?;
+    let load = |path: Option<String>| -> Result<_, ImageDataErrors> {
+        match path {
+            Some(path) => Ok(Some(load(path, &args.limits)?)),
+            None => Ok(None),
+        }
+    };
+
+    let paths = vec![args.occlusion, args.roughness, args.metallic, args.height];
+    let maps: Vec<_> = paths.into_par_iter().map(load).collect();
+    let mut maps = maps.into_iter().collect::<Result<Vec<_>, _>>()?.into_iter();
+    let maps = TextureMaps {
+        occlusion: maps.next().flatten(),
+        roughness: maps.next().flatten(),
+        metallic: maps.next().flatten(),
+        height: maps.next().flatten(),
+    };
+    let depth = [
+        &maps.occlusion,
+        &maps.roughness,
+        &maps.metallic,
+        &maps.height,
+    ]
+    .iter()
+    .filter_map(|map| map.as_ref().map(BitDepth::of))
+    .max()
+    .unwrap_or(BitDepth::Eight)
+    .for_format(output_format, ColorLayout::RGBA);
+    let packed = pack_textures(&maps, args.preset, depth)?;
+
+    save_image_with(&packed, &args.output, output_format, &args.encoder)
 }
 
-fn combine_images(image_1: DynamicImage, image_2: DynamicImage) -> Vec<u8> {
-    let vec_1 = image_1.to_rgba8().into_vec();
-    let vec_2 = image_2.to_rgba8().into_vec();
+fn montage(args: MontageArgs) -> Result<(), ImageDataErrors> {
+    use_threads(args.threads);
+    let output_format = get_output_format(&args.output, args.format)?;
+    let images = load_all(args.images, &args.limits)?;
 
-    return alternate_pixels(vec_1, vec_2);
-}
+    let layouts: Vec<_> = images.iter().map(ColorLayout::of).collect();
+    let layout = args
+        .montage
+        .output_layout(&layouts)
+        .for_format(output_format);
+    let depth = BitDepth::highest(&images).for_format(output_format, layout);
+    let sheet = layout.apply(rust_image_combiner::montage(images, &args.montage, depth)?);
 
-fn alternate_pixels(vec_1: Vec<u8>, vec_2: Vec<u8>) -> Vec<u8> {
-    let mut vec_out: Vec<u8> = Vec::new();
+    save_image_with(&sheet, &args.output, output_format, &args.encoder)
+}
 
-    for i in 0..vec_1.len() {
-        if i % 4 == 0 {
-            vec_out.push(vec_1[i]);
-        } else {
-            vec_out.push(vec_2[i]);
-        }
-    }
+fn concat(args: ConcatArgs) -> Result<(), ImageDataErrors> {
+    use_threads(args.threads);
+    let output_format = get_output_format(&args.output, args.format)?;
+    let images = load_all(args.images, &args.limits)?;
+
+    let layouts: Vec<_> = images.iter().map(ColorLayout::of).collect();
+    let dimensions: Vec<_> = images.iter().map(|image| image.dimensions()).collect();
+    let layout = args
+        .concat
+        .output_layout(&layouts, &dimensions)
+        .for_format(output_format);
+    let depth = BitDepth::highest(&images).for_format(output_format, layout);
+    let joined = layout.apply(concatenate(images, &args.concat, depth)?);
+
+    save_image_with(&joined, &args.output, output_format, &args.encoder)
+}
 
-    return vec_out;
+fn stereo(args: StereoArgs) -> Result<(), ImageDataErrors> {
+    use_threads(args.threads);
+    let output_format = get_output_format(&args.output, args.format)?;
+    let mut views = load_all(vec![args.left, args.right], &args.limits)?;
+
+    let layouts: Vec<_> = views.iter().map(ColorLayout::of).collect();
+    let layout = args
+        .anaglyph
+        .output_layout(&layouts)
+        .for_format(output_format);
+    let depth = BitDepth::highest(&views).for_format(output_format, layout);
+    let right = views.pop().expect("two views were loaded");
+    let left = views.pop().expect("two views were loaded");
+    let mixed = layout.apply(anaglyph(left, right, &args.anaglyph, args.parallax, depth)?);
+
+    save_image_with(&mixed, &args.output, output_format, &args.encoder)
 }
@@ -0,0 +1,213 @@
+use crate::canvas::{new_canvas, paste};
+use crate::depth::BitDepth;
+use crate::error::ImageDataErrors;
+use crate::layout::ColorLayout;
+use crate::resize::{Fit, SizeStrategy, TargetSize};
+use image::{DynamicImage, GenericImageView};
+use rayon::prelude::*;
+use std::convert::TryFrom;
+
+/// Lays images out on a grid, as for a contact sheet.
+///
+/// Images fill the grid row by row. Each one is brought to the cell size by
+/// [`Montage::cell`], whose target decides that size and whose background
+/// also fills the spacing, the margin and any cells left empty.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Montage {
+    /// The number of columns. When `None` it follows from `rows`, or the grid
+    /// is made as close to square as possible.
+    pub columns: Option<u32>,
+    /// The number of rows. When `None` it is however many the images need.
+    pub rows: Option<u32>,
+    pub cell: SizeStrategy,
+    /// Pixels between neighbouring cells.
+    pub spacing: u32,
+    /// Pixels around the outside of the grid.
+    pub margin: u32,
+}
+
+impl Default for Montage {
+    /// Cells the size of the largest input, which every input is fitted into
+    /// without changing its aspect ratio.
+    fn default() -> Self {
+        Montage {
+            columns: None,
+            rows: None,
+            cell: SizeStrategy {
+                target: TargetSize::Largest,
+                fit: Fit::Letterbox,
+                ..SizeStrategy::default()
+            },
+            spacing: 0,
+            margin: 0,
+        }
+    }
+}
+
+impl Montage {
+    /// Works out the columns and rows for `count` images.
+    pub fn grid(&self, count: usize) -> Result<(u32, u32), ImageDataErrors> {
+        let needed = count.max(1) as u64;
+        let (columns, rows) = match (self.columns, self.rows) {
+            (Some(columns), Some(rows)) => (columns, rows),
+            (Some(columns), None) => (columns, lines_for(needed, columns)),
+            (None, Some(rows)) => (lines_for(needed, rows), rows),
+            (None, None) => {
+                let columns = square_side(needed);
+                (columns, lines_for(needed, columns))
+            }
+        };
+
+        if u64::from(columns) * u64::from(rows) < needed {
+            return Err(ImageDataErrors::GridTooSmall(columns, rows, count));
+        }
+        Ok((columns, rows))
+    }
+
+    /// The channels the montage needs given the layout of each input: the
+    /// inputs', plus the background's if any of it shows.
+    pub fn output_layout(&self, inputs: &[ColorLayout]) -> ColorLayout {
+        let layout = ColorLayout::union_of(
+            &inputs
+                .iter()
+                .map(|&input| self.cell.output_layout(input))
+                .collect::<Vec<_>>(),
+        );
+        let cells = self
+            .grid(inputs.len())
+            .map_or(0, |(columns, rows)| u64::from(columns) * u64::from(rows));
+        let gaps = self.margin > 0 || (self.spacing > 0 && cells > 1);
+        if gaps || cells > inputs.len() as u64 {
+            layout.union(ColorLayout::of_pixel(self.cell.background))
+        } else {
+            layout
+        }
+    }
+}
+
+/// Arranges `images` on a grid following `montage`, producing an RGBA image
+/// of the given depth.
+pub fn montage(
+    images: Vec<DynamicImage>,
+    montage: &Montage,
+    depth: BitDepth,
+) -> Result<DynamicImage, ImageDataErrors> {
+    if images.is_empty() {
+        return Err(ImageDataErrors::NoInputImages);
+    }
+
+    let (columns, rows) = montage.grid(images.len())?;
+    let dimensions: Vec<_> = images.iter().map(|image| image.dimensions()).collect();
+    let cell = montage.cell.target_dimensions(&dimensions)?;
+    let too_large = || {
+        ImageDataErrors::ImageTooLarge(format!(
+            "{}x{} grid of {}x{} cells",
+            columns, rows, cell.0, cell.1
+        ))
+    };
+    let width = span(columns, cell.0, montage.spacing, montage.margin).ok_or_else(too_large)?;
+    let height = span(rows, cell.1, montage.spacing, montage.margin).ok_or_else(too_large)?;
+
+    let mut canvas = new_canvas(width, height, montage.cell.background, depth)?;
+    let cells: Vec<_> = images
+        .into_par_iter()
+        .map(|image| montage.cell.apply(image, cell))
+        .collect();
+    for (index, image) in cells.into_iter().enumerate() {
+        let (column, row) = (index as u32 % columns, index as u32 / columns);
+        let x = u64::from(montage.margin)
+            + u64::from(column) * (u64::from(cell.0) + u64::from(montage.spacing));
+        let y = u64::from(montage.margin)
+            + u64::from(row) * (u64::from(cell.1) + u64::from(montage.spacing));
+        paste(&mut canvas, image, x as i64, y as i64);
+    }
+
+    Ok(canvas)
+}
+
+/// The length of `count` cells of `cell` pixels with `spacing` between them
+/// and `margin` on both ends, if it fits in a `u32`.
+fn span(count: u32, cell: u32, spacing: u32, margin: u32) -> Option<u32> {
+    let length = u64::from(count) * u64::from(cell)
+        + u64::from(count.saturating_sub(1)) * u64::from(spacing)
+        + 2 * u64::from(margin);
+    u32::try_from(length).ok()
+}
+
+/// How many rows (or columns) of `per_line` cells `count` cells take up,
+/// saturating at `u32::MAX`. With no cells per line there is no answer, so
+/// this returns 0 and the grid is reported as too small.
+fn lines_for(count: u64, per_line: u32) -> u32 {
+    if per_line == 0 {
+        return 0;
+    }
+    u32::try_from(count.div_ceil(u64::from(per_line))).unwrap_or(u32::MAX)
+}
+
+/// The side of the smallest square grid with room for `count` cells.
+fn square_side(count: u64) -> u32 {
+    let mut side = (count as f64).sqrt() as u64;
+    while side * side < count {
+        side += 1;
+    }
+    u32::try_from(side).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn grids_follow_the_given_columns_and_rows() {
+        let grid = |columns, rows, count| {
+            Montage {
+                columns,
+                rows,
+                ..Montage::default()
+            }
+            .grid(count)
+        };
+        assert_eq!(grid(None, None, 5).unwrap(), (3, 2));
+        assert_eq!(grid(None, None, 9).unwrap(), (3, 3));
+        assert_eq!(grid(Some(2), None, 5).unwrap(), (2, 3));
+        assert_eq!(grid(None, Some(1), 5).unwrap(), (5, 1));
+        assert!(matches!(
+            grid(Some(2), Some(2), 5),
+            Err(ImageDataErrors::GridTooSmall(2, 2, 5))
+        ));
+    }
+
+    #[test]
+    fn cells_are_placed_between_margin_and_spacing() {
+        let black = Rgba([0, 0, 0, 255]);
+        let images = (0..5)
+            .map(|i| {
+                DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 3, Rgba([i * 50, 0, 0, 255])))
+            })
+            .collect();
+        let layout = Montage {
+            columns: Some(3),
+            spacing: 2,
+            margin: 1,
+            cell: SizeStrategy {
+                background: black,
+                ..Montage::default().cell
+            },
+            ..Montage::default()
+        };
+
+        let sheet = montage(images, &layout, BitDepth::Eight)
+            .unwrap()
+            .into_rgba8();
+        assert_eq!(sheet.dimensions(), (3 * 4 + 2 * 2 + 2, 2 * 3 + 2 + 2));
+        // The fifth image is the second cell of the second row.
+        let (x, y) = (1 + 4 + 2, 1 + 3 + 2);
+        assert_eq!(*sheet.get_pixel(x, y), Rgba([200, 0, 0, 255]));
+        assert_eq!(*sheet.get_pixel(x + 3, y + 2), Rgba([200, 0, 0, 255]));
+        assert_eq!(*sheet.get_pixel(x - 1, y), black);
+        assert_eq!(*sheet.get_pixel(x, y - 1), black);
+        // The last cell is empty.
+        assert_eq!(*sheet.get_pixel(x + 6, y), black);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::buffer;
+use crate::depth::{BitDepth, Channel};
+use crate::error::ImageDataErrors;
+use image::{imageops::FilterType::Nearest, DynamicImage, ImageBuffer, Luma, Pixel};
+use std::str::FromStr;
+
+/// The grayscale maps that can be packed into a texture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureMap {
+    Occlusion,
+    Roughness,
+    Metallic,
+    Height,
+}
+
+/// How one output channel of a packed texture is filled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PackChannel {
+    /// The map to read, or `None` to always use `missing`.
+    pub map: Option<TextureMap>,
+    /// Store the complement of the value, e.g. to turn roughness into
+    /// smoothness.
+    pub invert: bool,
+    /// The value written when the map was not supplied, on an 8-bit scale.
+    pub missing: u8,
+}
+
+impl PackChannel {
+    const fn map(map: TextureMap, missing: u8) -> Self {
+        PackChannel {
+            map: Some(map),
+            invert: false,
+            missing,
+        }
+    }
+
+    const fn constant(value: u8) -> Self {
+        PackChannel {
+            map: None,
+            invert: false,
+            missing: value,
+        }
+    }
+}
+
+/// Well known channel layouts for packed game textures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PackPreset {
+    /// Unreal ORM: occlusion, roughness, metallic, with height in alpha.
+    UnrealOrm,
+    /// Unity HDRP mask map: metallic, occlusion, detail mask, smoothness.
+    UnityMaskMap,
+}
+
+impl PackPreset {
+    /// The channels of the preset, in R, G, B, A order.
+    pub fn channels(self) -> [PackChannel; 4] {
+        match self {
+            PackPreset::UnrealOrm => [
+                PackChannel::map(TextureMap::Occlusion, 255),
+                PackChannel::map(TextureMap::Roughness, 255),
+                PackChannel::map(TextureMap::Metallic, 0),
+                PackChannel::map(TextureMap::Height, 255),
+            ],
+            PackPreset::UnityMaskMap => [
+                PackChannel::map(TextureMap::Metallic, 0),
+                PackChannel::map(TextureMap::Occlusion, 255),
+                PackChannel::constant(255),
+                PackChannel {
+                    map: Some(TextureMap::Roughness),
+                    invert: true,
+                    missing: 128,
+                },
+            ],
+        }
+    }
+}
+
+impl FromStr for PackPreset {
+    type Err = ImageDataErrors;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "orm" | "unreal" => Ok(PackPreset::UnrealOrm),
+            "mask" | "hdrp" => Ok(PackPreset::UnityMaskMap),
+            _ => Err(ImageDataErrors::UnknownPackPreset(name.to_string())),
+        }
+    }
+}
+
+/// The maps to pack. Any of them may be left out.
+#[derive(Default)]
+pub struct TextureMaps {
+    pub occlusion: Option<DynamicImage>,
+    pub roughness: Option<DynamicImage>,
+    pub metallic: Option<DynamicImage>,
+    pub height: Option<DynamicImage>,
+}
+
+impl TextureMaps {
+    fn get(&self, map: TextureMap) -> Option<&DynamicImage> {
+        match map {
+            TextureMap::Occlusion => self.occlusion.as_ref(),
+            TextureMap::Roughness => self.roughness.as_ref(),
+            TextureMap::Metallic => self.metallic.as_ref(),
+            TextureMap::Height => self.height.as_ref(),
+        }
+    }
+}
+
+/// Packs grayscale maps into the channels of one RGBA image of the given
+/// depth following `preset`.
+///
+/// At least two of the maps the preset uses must be supplied. They are all
+/// resized to the smallest of them. Maps that were not supplied are filled
+/// with the preset's default for that channel.
+pub fn pack_textures(
+    maps: &TextureMaps,
+    preset: PackPreset,
+    depth: BitDepth,
+) -> Result<DynamicImage, ImageDataErrors> {
+    let channels = preset.channels();
+    let supplied: Vec<_> = channels
+        .iter()
+        .filter_map(|channel| maps.get(channel.map?))
+        .collect();
+    if supplied.len() < 2 {
+        return Err(ImageDataErrors::TooFewMaps(supplied.len()));
+    }
+    let (width, height) = supplied
+        .iter()
+        .map(|image| (image.width(), image.height()))
+        .reduce(crate::get_smallest_dimensions)
+        .ok_or(ImageDataErrors::NoInputImages)?;
+
+    let planes: Vec<Option<DynamicImage>> = channels
+        .iter()
+        .map(|channel| {
+            let image = maps.get(channel.map?)?;
+            Some(image.resize_exact(width, height, Nearest))
+        })
+        .collect();
+
+    Ok(match depth {
+        BitDepth::Eight => {
+            let planes = planes
+                .iter()
+                .map(|plane| plane.as_ref().map(|p| p.to_luma8()));
+            DynamicImage::ImageRgba8(pack(&channels, planes.collect(), width, height)?)
+        }
+        BitDepth::Sixteen => {
+            let planes = planes
+                .iter()
+                .map(|plane| plane.as_ref().map(|p| p.to_luma16()));
+            DynamicImage::ImageRgba16(pack(&channels, planes.collect(), width, height)?)
+        }
+        BitDepth::Float => {
+            let planes = planes
+                .iter()
+                .map(|plane| plane.as_ref().map(|p| p.to_luma32f()));
+            DynamicImage::ImageRgba32F(pack(&channels, planes.collect(), width, height)?)
+        }
+    })
+}
+
+/// One grayscale map, resized to the output.
+type Plane<T> = ImageBuffer<Luma<T>, Vec<T>>;
+
+/// Fills the channels of a new image from `planes`, one per channel of
+/// `channels`.
+fn pack<P>(
+    channels: &[PackChannel; 4],
+    planes: Vec<Option<Plane<P::Subpixel>>>,
+    width: u32,
+    height: u32,
+) -> Result<ImageBuffer<P, Vec<P::Subpixel>>, ImageDataErrors>
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let name = format!("{}x{}", width, height);
+    let mut output: ImageBuffer<P, _> = buffer::new_image(width, height, &name)?;
+    let data: &mut [P::Subpixel] = &mut output;
+    for (i, channel) in channels.iter().enumerate() {
+        let missing = P::Subpixel::from_unit(channel.missing.to_unit());
+        let values = data.iter_mut().skip(i).step_by(4);
+        match &planes[i] {
+            Some(plane) if channel.invert => {
+                for (out, value) in values.zip(plane.iter()) {
+                    *out = P::Subpixel::from_unit(1.0 - value.to_unit());
+                }
+            }
+            Some(plane) => {
+                for (out, value) in values.zip(plane.iter()) {
+                    *out = *value;
+                }
+            }
+            None => values.for_each(|out| *out = missing),
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GrayImage;
+
+    /// A 3x2 map holding `value` everywhere.
+    fn map(value: u8) -> Option<DynamicImage> {
+        Some(DynamicImage::ImageLuma8(GrayImage::from_pixel(
+            3,
+            2,
+            Luma([value]),
+        )))
+    }
+
+    fn packed_pixel(maps: &TextureMaps, preset: PackPreset) -> [u8; 4] {
+        let packed = pack_textures(maps, preset, BitDepth::Eight).unwrap();
+        let packed = packed.as_rgba8().expect("8-bit packs are RGBA8");
+        assert_eq!(packed.dimensions(), (3, 2));
+        packed.get_pixel(2, 1).0
+    }
+
+    #[test]
+    fn unreal_orm_packs_occlusion_roughness_metallic_and_height() {
+        let maps = TextureMaps {
+            occlusion: map(10),
+            roughness: map(20),
+            metallic: map(30),
+            height: map(40),
+        };
+        assert_eq!(packed_pixel(&maps, PackPreset::UnrealOrm), [10, 20, 30, 40]);
+
+        let maps = TextureMaps {
+            occlusion: map(10),
+            roughness: map(20),
+            ..TextureMaps::default()
+        };
+        assert_eq!(packed_pixel(&maps, PackPreset::UnrealOrm), [10, 20, 0, 255]);
+    }
+
+    #[test]
+    fn unity_mask_map_packs_metallic_occlusion_and_smoothness() {
+        let maps = TextureMaps {
+            occlusion: map(10),
+            roughness: map(20),
+            metallic: map(30),
+            height: map(40),
+        };
+        assert_eq!(
+            packed_pixel(&maps, PackPreset::UnityMaskMap),
+            [30, 10, 255, 235]
+        );
+
+        let maps = TextureMaps {
+            occlusion: map(10),
+            metallic: map(30),
+            ..TextureMaps::default()
+        };
+        assert_eq!(
+            packed_pixel(&maps, PackPreset::UnityMaskMap),
+            [30, 10, 255, 128]
+        );
+    }
+
+    #[test]
+    fn fewer_than_two_used_maps_are_refused() {
+        // The mask map has no height channel, so only one map is used.
+        let maps = TextureMaps {
+            roughness: map(20),
+            height: map(40),
+            ..TextureMaps::default()
+        };
+        let result = pack_textures(&maps, PackPreset::UnityMaskMap, BitDepth::Eight);
+        assert!(matches!(result, Err(ImageDataErrors::TooFewMaps(1))));
+    }
+}
@@ -0,0 +1,615 @@
+use crate::color::{linear_to_srgb, srgb_to_linear};
+use crate::depth::{BitDepth, Channel};
+use crate::error::ImageDataErrors;
+use crate::layout::ColorLayout;
+use image::{
+    imageops, imageops::FilterType, DynamicImage, GenericImageView, ImageBuffer, Pixel, Rgba,
+};
+use std::str::FromStr;
+
+/// The dimensions every input is brought to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TargetSize {
+    /// The input with the fewest pixels.
+    Smallest,
+    /// The input with the most pixels.
+    Largest,
+    /// The input at this index, counted from 0.
+    Input(usize),
+    /// An explicit width and height.
+    Exact(u32, u32),
+}
+
+impl FromStr for TargetSize {
+    type Err = ImageDataErrors;
+
+    /// Parses `smallest`, `largest`, `first`, `second`, `input-N` (counted
+    /// from 1) or `WxH`.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = || ImageDataErrors::InvalidSizeStrategy(spec.to_string());
+
+        match spec {
+            "smallest" | "shrink" => return Ok(TargetSize::Smallest),
+            "largest" | "grow" => return Ok(TargetSize::Largest),
+            "first" => return Ok(TargetSize::Input(0)),
+            "second" => return Ok(TargetSize::Input(1)),
+            _ => {}
+        }
+
+        if let Some(index) = spec.strip_prefix("input-") {
+            return match index.parse::<usize>() {
+                Ok(index) if index >= 1 => Ok(TargetSize::Input(index - 1)),
+                _ => Err(invalid()),
+            };
+        }
+
+        let (width, height) = spec.split_once('x').ok_or_else(invalid)?;
+        match (width.parse::<u32>(), height.parse::<u32>()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => {
+                Ok(TargetSize::Exact(width, height))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How an input is made to cover the target size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fit {
+    /// Resize to exactly the target size, ignoring aspect ratio.
+    Stretch,
+    /// Scale to fit inside the target, filling the rest with the background.
+    Letterbox,
+    /// Scale to cover the target, cropping whatever sticks out.
+    Crop,
+    /// Keep the original size, cropping or padding with the background.
+    None,
+}
+
+impl FromStr for Fit {
+    type Err = ImageDataErrors;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec {
+            "stretch" => Ok(Fit::Stretch),
+            "letterbox" | "contain" => Ok(Fit::Letterbox),
+            "crop" | "fill" | "cover" => Ok(Fit::Crop),
+            "none" => Ok(Fit::None),
+            _ => Err(ImageDataErrors::InvalidSizeStrategy(spec.to_string())),
+        }
+    }
+}
+
+/// Where an input is placed inside the target when it does not cover it
+/// exactly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// Returns the offset of an `inner` sized area placed inside `outer`.
+    /// The offset is negative when `inner` is the larger one.
+    pub fn offset(self, outer: (u32, u32), inner: (u32, u32)) -> (i64, i64) {
+        let free_x = outer.0 as i64 - inner.0 as i64;
+        let free_y = outer.1 as i64 - inner.1 as i64;
+        let x = match self {
+            Anchor::TopLeft | Anchor::Left | Anchor::BottomLeft => 0,
+            Anchor::Top | Anchor::Center | Anchor::Bottom => free_x / 2,
+            Anchor::TopRight | Anchor::Right | Anchor::BottomRight => free_x,
+        };
+        let y = match self {
+            Anchor::TopLeft | Anchor::Top | Anchor::TopRight => 0,
+            Anchor::Left | Anchor::Center | Anchor::Right => free_y / 2,
+            Anchor::BottomLeft | Anchor::Bottom | Anchor::BottomRight => free_y,
+        };
+        (x, y)
+    }
+}
+
+impl FromStr for Anchor {
+    type Err = ImageDataErrors;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec {
+            "top-left" => Ok(Anchor::TopLeft),
+            "top" => Ok(Anchor::Top),
+            "top-right" => Ok(Anchor::TopRight),
+            "left" => Ok(Anchor::Left),
+            "center" => Ok(Anchor::Center),
+            "right" => Ok(Anchor::Right),
+            "bottom-left" => Ok(Anchor::BottomLeft),
+            "bottom" => Ok(Anchor::Bottom),
+            "bottom-right" => Ok(Anchor::BottomRight),
+            _ => Err(ImageDataErrors::InvalidSizeStrategy(spec.to_string())),
+        }
+    }
+}
+
+/// The resampling filter used when an input is resized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+    /// Pick a filter from the scale factor: Lanczos3 when shrinking to less
+    /// than half size, Triangle for milder shrinking and CatmullRom when
+    /// enlarging.
+    Auto,
+}
+
+impl ResizeFilter {
+    /// Returns the filter to use for resizing `from` to `to`.
+    pub fn choose(self, from: (u32, u32), to: (u32, u32)) -> FilterType {
+        match self {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+            ResizeFilter::Auto => {
+                let scale = f64::min(to.0 as f64 / from.0 as f64, to.1 as f64 / from.1 as f64);
+                if scale < 0.5 {
+                    FilterType::Lanczos3
+                } else if scale < 1.0 {
+                    FilterType::Triangle
+                } else {
+                    FilterType::CatmullRom
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for ResizeFilter {
+    type Err = ImageDataErrors;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec {
+            "nearest" => Ok(ResizeFilter::Nearest),
+            "triangle" | "bilinear" => Ok(ResizeFilter::Triangle),
+            "catmull-rom" | "bicubic" => Ok(ResizeFilter::CatmullRom),
+            "gaussian" => Ok(ResizeFilter::Gaussian),
+            "lanczos3" | "lanczos" => Ok(ResizeFilter::Lanczos3),
+            "auto" => Ok(ResizeFilter::Auto),
+            _ => Err(ImageDataErrors::InvalidSizeStrategy(spec.to_string())),
+        }
+    }
+}
+
+/// How inputs of different sizes are brought to the same dimensions.
+///
+/// The default shrinks everything to the smallest input, stretching as
+/// needed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeStrategy {
+    pub target: TargetSize,
+    pub fit: Fit,
+    pub anchor: Anchor,
+    /// Fills whatever part of the target an input does not cover.
+    pub background: Rgba<u8>,
+    pub filter: ResizeFilter,
+    /// Shrink images in linear light instead of on sRGB encoded values, which
+    /// keeps fine bright detail from darkening.
+    pub linear_light: bool,
+}
+
+impl Default for SizeStrategy {
+    fn default() -> Self {
+        SizeStrategy {
+            target: TargetSize::Smallest,
+            fit: Fit::Stretch,
+            anchor: Anchor::Center,
+            background: Rgba([0, 0, 0, 0]),
+            filter: ResizeFilter::Nearest,
+            linear_light: false,
+        }
+    }
+}
+
+impl SizeStrategy {
+    /// Works out the target dimensions for the given input dimensions.
+    pub fn target_dimensions(
+        &self,
+        dimensions: &[(u32, u32)],
+    ) -> Result<(u32, u32), ImageDataErrors> {
+        let target = match self.target {
+            TargetSize::Smallest => dimensions.iter().copied().reduce(get_smallest_dimensions),
+            TargetSize::Largest => dimensions.iter().copied().reduce(get_largest_dimensions),
+            TargetSize::Input(index) => {
+                let dimensions = dimensions.get(index).copied();
+                if dimensions.is_none() {
+                    return Err(ImageDataErrors::InvalidSizeStrategy(format!(
+                        "input-{}",
+                        index + 1
+                    )));
+                }
+                dimensions
+            }
+            TargetSize::Exact(width, height) => Some((width, height)),
+        };
+        target.ok_or(ImageDataErrors::NoInputImages)
+    }
+
+    /// The channels an input with `layout` needs once brought to size, which
+    /// includes the background wherever it can show through.
+    pub fn output_layout(&self, layout: ColorLayout) -> ColorLayout {
+        match self.fit {
+            Fit::Stretch | Fit::Crop => layout,
+            Fit::Letterbox | Fit::None => layout.union(ColorLayout::of_pixel(self.background)),
+        }
+    }
+
+    /// Brings `image` to `(width, height)`, keeping its bit depth.
+    pub fn apply(&self, image: DynamicImage, (width, height): (u32, u32)) -> DynamicImage {
+        if image.dimensions() == (width, height) {
+            return image;
+        }
+
+        let image = match self.fit {
+            Fit::Stretch => return self.resize(&image, (width, height)),
+            Fit::Letterbox => {
+                let scaled = contain_dimensions(image.dimensions(), (width, height));
+                self.resize(&image, scaled)
+            }
+            Fit::Crop => {
+                let scaled = cover_dimensions(image.dimensions(), (width, height));
+                self.resize(&image, scaled)
+            }
+            Fit::None => image,
+        };
+
+        let offset = self.anchor.offset((width, height), image.dimensions());
+        let size = (width, height);
+        match BitDepth::of(&image) {
+            BitDepth::Eight => {
+                DynamicImage::ImageRgba8(self.place(&image.to_rgba8(), size, offset))
+            }
+            BitDepth::Sixteen => {
+                DynamicImage::ImageRgba16(self.place(&image.to_rgba16(), size, offset))
+            }
+            BitDepth::Float => {
+                DynamicImage::ImageRgba32F(self.place(&image.to_rgba32f(), size, offset))
+            }
+        }
+    }
+
+    /// Copies `image` as it is to `(x, y)` on a canvas filled with the
+    /// background, which shows only where the image does not reach.
+    fn place<P>(
+        &self,
+        image: &ImageBuffer<P, Vec<P::Subpixel>>,
+        (width, height): (u32, u32),
+        (x, y): (i64, i64),
+    ) -> ImageBuffer<P, Vec<P::Subpixel>>
+    where
+        P: Pixel,
+        P::Subpixel: Channel,
+    {
+        let background = self
+            .background
+            .0
+            .map(|value| P::Subpixel::from_unit(value.to_unit()));
+        let mut canvas = ImageBuffer::from_pixel(width, height, *P::from_slice(&background));
+        imageops::replace(&mut canvas, image, x, y);
+        canvas
+    }
+
+    /// Resizes `image` to exactly `(width, height)` with the chosen filter,
+    /// keeping its bit depth.
+    pub fn resize(&self, image: &DynamicImage, (width, height): (u32, u32)) -> DynamicImage {
+        if image.dimensions() == (width, height) {
+            return image.clone();
+        }
+
+        let filter = self.filter.choose(image.dimensions(), (width, height));
+        if BitDepth::of(image) == BitDepth::Float {
+            // Float pixels are linear already, so there is nothing to convert.
+            return resize_float(image, (width, height), filter);
+        }
+        let shrinking = width < image.width() || height < image.height();
+        if !(self.linear_light && shrinking) {
+            return image.resize_exact(width, height, filter);
+        }
+
+        let mut linear = image.to_rgba32f();
+        for pixel in linear.pixels_mut() {
+            for channel in &mut pixel.0[..3] {
+                *channel = srgb_to_linear(*channel);
+            }
+        }
+        let mut resized = imageops::resize(&linear, width, height, filter);
+        for pixel in resized.pixels_mut() {
+            for channel in &mut pixel.0[..3] {
+                *channel = linear_to_srgb(*channel);
+            }
+        }
+        let resized = DynamicImage::ImageRgba32F(resized);
+        match BitDepth::of(image) {
+            BitDepth::Sixteen => DynamicImage::ImageRgba16(resized.to_rgba16()),
+            _ => DynamicImage::ImageRgba8(resized.to_rgba8()),
+        }
+    }
+}
+
+/// Resizes a float `image` without clipping HDR values.
+///
+/// The resampler clamps to `0.0..=1.0`, so color is scaled into that range
+/// by its brightest value and back afterwards; filtering is linear, so this
+/// gives the same result as filtering the values unscaled.
+fn resize_float(
+    image: &DynamicImage,
+    (width, height): (u32, u32),
+    filter: FilterType,
+) -> DynamicImage {
+    let mut pixels = image.to_rgba32f();
+    let peak = pixels
+        .pixels()
+        .flat_map(|pixel| pixel.0.into_iter().take(3))
+        .filter(|value| value.is_finite())
+        .fold(1.0, f32::max);
+    for pixel in pixels.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel /= peak;
+        }
+    }
+    let mut resized = imageops::resize(&pixels, width, height, filter);
+    for pixel in resized.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel *= peak;
+        }
+    }
+
+    let resized = DynamicImage::ImageRgba32F(resized);
+    match image {
+        DynamicImage::ImageRgb32F(_) => DynamicImage::ImageRgb32F(resized.to_rgb32f()),
+        _ => resized,
+    }
+}
+
+/// Returns whichever of the two dimensions covers fewer pixels.
+pub fn get_smallest_dimensions(dim_1: (u32, u32), dim_2: (u32, u32)) -> (u32, u32) {
+    let pix_1 = u64::from(dim_1.0) * u64::from(dim_1.1);
+    let pix_2 = u64::from(dim_2.0) * u64::from(dim_2.1);
+
+    if pix_1 < pix_2 {
+        dim_1
+    } else {
+        dim_2
+    }
+}
+
+/// Returns whichever of the two dimensions covers more pixels.
+pub fn get_largest_dimensions(dim_1: (u32, u32), dim_2: (u32, u32)) -> (u32, u32) {
+    let pix_1 = u64::from(dim_1.0) * u64::from(dim_1.1);
+    let pix_2 = u64::from(dim_2.0) * u64::from(dim_2.1);
+
+    if pix_1 > pix_2 {
+        dim_1
+    } else {
+        dim_2
+    }
+}
+
+/// The largest size with the aspect ratio of `image` that fits inside `target`.
+fn contain_dimensions(image: (u32, u32), target: (u32, u32)) -> (u32, u32) {
+    let scale = f64::min(
+        target.0 as f64 / image.0 as f64,
+        target.1 as f64 / image.1 as f64,
+    );
+    (
+        ((image.0 as f64 * scale).round() as u32).clamp(1, target.0),
+        ((image.1 as f64 * scale).round() as u32).clamp(1, target.1),
+    )
+}
+
+/// The smallest size with the aspect ratio of `image` that covers `target`.
+fn cover_dimensions(image: (u32, u32), target: (u32, u32)) -> (u32, u32) {
+    let scale = f64::max(
+        target.0 as f64 / image.0 as f64,
+        target.1 as f64 / image.1 as f64,
+    );
+    (
+        ((image.0 as f64 * scale).round() as u32).max(target.0),
+        ((image.1 as f64 * scale).round() as u32).max(target.1),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn linear_shrinking_keeps_float_values() {
+        let image = ImageBuffer::from_pixel(4, 2, Rgb([6.0, 3.0, 0.25]));
+        let strategy = SizeStrategy {
+            filter: ResizeFilter::Triangle,
+            linear_light: true,
+            ..SizeStrategy::default()
+        };
+
+        let resized = strategy.resize(&DynamicImage::ImageRgb32F(image), (2, 1));
+        let resized = resized.as_rgb32f().expect("float images stay float");
+        for pixel in resized.pixels() {
+            for (value, expected) in pixel.0.iter().zip([6.0, 3.0, 0.25]) {
+                assert!((value - expected).abs() < 1e-5, "{:?}", pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn placed_inputs_are_not_blended_with_the_background() {
+        let red = Rgba([255, 0, 0, 127]);
+        let blue = Rgba([0, 0, 255, 255]);
+        let strategy = SizeStrategy {
+            fit: Fit::None,
+            anchor: Anchor::TopLeft,
+            background: blue,
+            ..SizeStrategy::default()
+        };
+
+        let image = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, red));
+        let placed = strategy.apply(image, (2, 1)).into_rgba8();
+        assert_eq!(placed.as_raw().as_slice(), [red.0, blue.0].concat());
+    }
+
+    #[test]
+    fn anchors_offset_into_every_corner_edge_and_the_center() {
+        let (outer, inner) = ((10, 8), (4, 3));
+        let expected = [
+            (Anchor::TopLeft, (0, 0)),
+            (Anchor::Top, (3, 0)),
+            (Anchor::TopRight, (6, 0)),
+            (Anchor::Left, (0, 2)),
+            (Anchor::Center, (3, 2)),
+            (Anchor::Right, (6, 2)),
+            (Anchor::BottomLeft, (0, 5)),
+            (Anchor::Bottom, (3, 5)),
+            (Anchor::BottomRight, (6, 5)),
+        ];
+        for (anchor, offset) in expected {
+            assert_eq!(anchor.offset(outer, inner), offset, "{:?}", anchor);
+        }
+        // A larger inner area is cropped, so the offsets turn negative.
+        assert_eq!(Anchor::Center.offset(inner, outer), (-3, -2));
+        assert_eq!(Anchor::BottomRight.offset(inner, outer), (-6, -5));
+    }
+
+    #[test]
+    fn contain_fits_inside_and_cover_covers_the_target() {
+        assert_eq!(contain_dimensions((400, 200), (100, 100)), (100, 50));
+        assert_eq!(contain_dimensions((200, 400), (100, 100)), (50, 100));
+        assert_eq!(contain_dimensions((10, 10), (30, 20)), (20, 20));
+        assert_eq!(contain_dimensions((1000, 1), (10, 10)), (10, 1));
+
+        assert_eq!(cover_dimensions((400, 200), (100, 100)), (200, 100));
+        assert_eq!(cover_dimensions((200, 400), (100, 100)), (100, 200));
+        assert_eq!(cover_dimensions((10, 10), (30, 20)), (30, 30));
+    }
+
+    #[test]
+    fn letterbox_and_crop_end_at_the_target_size() {
+        let image = DynamicImage::new_rgba8(40, 20);
+        for fit in [Fit::Stretch, Fit::Letterbox, Fit::Crop, Fit::None] {
+            let strategy = SizeStrategy {
+                fit,
+                ..SizeStrategy::default()
+            };
+            let resized = strategy.apply(image.clone(), (15, 15));
+            assert_eq!(resized.dimensions(), (15, 15), "{:?}", fit);
+        }
+    }
+
+    #[test]
+    fn target_sizes_pick_the_right_input() {
+        let dimensions = [(30, 10), (8, 8), (20, 20)];
+        let target = |target| {
+            SizeStrategy {
+                target,
+                ..SizeStrategy::default()
+            }
+            .target_dimensions(&dimensions)
+        };
+        assert_eq!(target(TargetSize::Smallest).unwrap(), (8, 8));
+        assert_eq!(target(TargetSize::Largest).unwrap(), (20, 20));
+        assert_eq!(target(TargetSize::Input(0)).unwrap(), (30, 10));
+        assert_eq!(target(TargetSize::Exact(7, 5)).unwrap(), (7, 5));
+        assert!(target(TargetSize::Input(3)).is_err());
+        assert_eq!(
+            "input-2".parse::<TargetSize>().unwrap(),
+            TargetSize::Input(1)
+        );
+        assert_eq!(
+            "12x34".parse::<TargetSize>().unwrap(),
+            TargetSize::Exact(12, 34)
+        );
+        assert!("0x34".parse::<TargetSize>().is_err());
+    }
+
+    #[test]
+    fn auto_picks_a_filter_from_the_scale() {
+        let auto = |to| ResizeFilter::Auto.choose((100, 100), to);
+        assert_eq!(auto((40, 100)), FilterType::Lanczos3);
+        assert_eq!(auto((50, 50)), FilterType::Triangle);
+        assert_eq!(auto((99, 100)), FilterType::Triangle);
+        assert_eq!(auto((100, 100)), FilterType::CatmullRom);
+        assert_eq!(auto((300, 200)), FilterType::CatmullRom);
+        let fixed = ResizeFilter::Gaussian.choose((100, 100), (10, 10));
+        assert_eq!(fixed, FilterType::Gaussian);
+    }
+
+    #[test]
+    fn parses_filter_names_and_aliases() {
+        let parse = |spec: &str| spec.parse::<ResizeFilter>().unwrap();
+        assert_eq!(parse("nearest"), ResizeFilter::Nearest);
+        assert_eq!(parse("bilinear"), ResizeFilter::Triangle);
+        assert_eq!(parse("bicubic"), ResizeFilter::CatmullRom);
+        assert_eq!(parse("gaussian"), ResizeFilter::Gaussian);
+        assert_eq!(parse("lanczos"), ResizeFilter::Lanczos3);
+        assert_eq!(parse("auto"), ResizeFilter::Auto);
+        assert!("cubic".parse::<ResizeFilter>().is_err());
+    }
+
+    /// Shrinks a black and white checkerboard to a single pixel.
+    fn shrink_checkerboard(linear_light: bool, image: DynamicImage) -> DynamicImage {
+        let strategy = SizeStrategy {
+            filter: ResizeFilter::Triangle,
+            linear_light,
+            ..SizeStrategy::default()
+        };
+        strategy.resize(&image, (1, 1))
+    }
+
+    #[test]
+    fn linear_shrinking_averages_light_not_encoded_values() {
+        let checkerboard = ImageBuffer::from_fn(2, 2, |x, y| {
+            let value = if (x + y) % 2 == 0 { 255 } else { 0 };
+            Rgba([value, value, value, 255])
+        });
+        let checkerboard = DynamicImage::ImageRgba8(checkerboard);
+
+        let plain = shrink_checkerboard(false, checkerboard.clone());
+        let plain = plain.as_rgba8().expect("8-bit images stay 8-bit");
+        assert!(plain.get_pixel(0, 0)[0].abs_diff(128) <= 1);
+
+        // Half of the light is 0.5 linear, which sRGB encodes as about 0.735.
+        let linear = shrink_checkerboard(true, checkerboard.clone());
+        let linear = linear.as_rgba8().expect("8-bit images stay 8-bit");
+        assert_eq!(linear.get_pixel(0, 0).0, [188, 188, 188, 255]);
+
+        let deep = shrink_checkerboard(true, DynamicImage::ImageRgba16(checkerboard.to_rgba16()));
+        let deep = deep.as_rgba16().expect("16-bit images stay 16-bit");
+        assert!(deep.get_pixel(0, 0)[0].abs_diff(48_192) <= 16);
+    }
+
+    #[test]
+    fn enlarging_ignores_linear_light() {
+        let image = DynamicImage::ImageRgba8(ImageBuffer::from_fn(2, 1, |x, _| {
+            let value = x as u8 * 255;
+            Rgba([value, value, value, 255])
+        }));
+        let strategy = SizeStrategy {
+            filter: ResizeFilter::Triangle,
+            ..SizeStrategy::default()
+        };
+        let linear = SizeStrategy {
+            linear_light: true,
+            ..strategy
+        };
+        assert_eq!(
+            strategy.resize(&image, (4, 2)),
+            linear.resize(&image, (4, 2))
+        );
+    }
+}
@@ -0,0 +1,647 @@
+//! Vectorized 8-bit combine loops.
+//!
+//! The pixel math of the blend modes and compositing operators is written
+//! once, generically over [`Lanes`]. The scalar combine path runs it on plain
+//! `f32`s; here it runs on 4 (SSE2) or 8 (AVX2) pixels at a time, one channel
+//! per register, with the instruction set picked at run time. Pixels left
+//! over at the end of a row take the scalar path, so every path writes the
+//! same bytes.
+
+use crate::combiner::check_sizes;
+use crate::depth::Channel;
+use crate::error::ImageDataErrors;
+use image::RgbaImage;
+use rayon::prelude::*;
+use std::ops::{Add, Div, Mul, Sub};
+
+/// Some number of `f32`s worked on together: a plain `f32`, or one SIMD
+/// register holding the same channel of several pixels.
+pub(crate) trait Lanes:
+    Copy + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self>
+{
+    /// The result of a comparison, one flag per lane.
+    type Mask: Copy;
+
+    /// How many RGBA8 pixels [`Lanes::load`] and [`Lanes::store`] handle.
+    const PIXELS: usize;
+
+    fn splat(value: f32) -> Self;
+    fn min(self, other: Self) -> Self;
+    fn max(self, other: Self) -> Self;
+    /// Clamps every lane to `0.0..=1.0`.
+    fn clamp_unit(self) -> Self;
+    fn abs(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn le(self, other: Self) -> Self::Mask;
+    fn ge(self, other: Self) -> Self::Mask;
+    fn gt(self, other: Self) -> Self::Mask;
+    fn eq(self, other: Self) -> Self::Mask;
+    /// Takes each lane from `if_true` where `mask` is set, else `if_false`.
+    fn select(mask: Self::Mask, if_true: Self, if_false: Self) -> Self;
+
+    /// Reads [`Lanes::PIXELS`] RGBA8 pixels as one value per channel, scaled
+    /// like [`Channel::to_unit`].
+    fn load(bytes: &[u8]) -> [Self; 4];
+
+    /// Writes channels back as RGBA8 pixels, clamped and rounded like
+    /// [`Channel::from_unit`].
+    fn store(channels: [Self; 4], bytes: &mut [u8]);
+}
+
+impl Lanes for f32 {
+    type Mask = bool;
+
+    const PIXELS: usize = 1;
+
+    #[inline(always)]
+    fn splat(value: f32) -> Self {
+        value
+    }
+
+    #[inline(always)]
+    fn min(self, other: Self) -> Self {
+        f32::min(self, other)
+    }
+
+    #[inline(always)]
+    fn max(self, other: Self) -> Self {
+        f32::max(self, other)
+    }
+
+    #[inline(always)]
+    fn clamp_unit(self) -> Self {
+        self.clamp(0.0, 1.0)
+    }
+
+    #[inline(always)]
+    fn abs(self) -> Self {
+        f32::abs(self)
+    }
+
+    #[inline(always)]
+    fn sqrt(self) -> Self {
+        f32::sqrt(self)
+    }
+
+    #[inline(always)]
+    fn le(self, other: Self) -> bool {
+        self <= other
+    }
+
+    #[inline(always)]
+    fn ge(self, other: Self) -> bool {
+        self >= other
+    }
+
+    #[inline(always)]
+    fn gt(self, other: Self) -> bool {
+        self > other
+    }
+
+    #[inline(always)]
+    fn eq(self, other: Self) -> bool {
+        self == other
+    }
+
+    #[inline(always)]
+    fn select(mask: bool, if_true: Self, if_false: Self) -> Self {
+        if mask {
+            if_true
+        } else {
+            if_false
+        }
+    }
+
+    #[inline(always)]
+    fn load(bytes: &[u8]) -> [Self; 4] {
+        [0, 1, 2, 3].map(|c| bytes[c].to_unit())
+    }
+
+    #[inline(always)]
+    fn store(channels: [Self; 4], bytes: &mut [u8]) {
+        for (byte, value) in bytes.iter_mut().zip(channels) {
+            *byte = u8::from_unit(value);
+        }
+    }
+}
+
+/// Pixel math that can be run on any [`Lanes`]: layers `source` over
+/// `backdrop`, both given as RGBA channels.
+pub(crate) trait PixelKernel: Sync {
+    fn apply<L: Lanes>(&self, backdrop: [L; 4], source: [L; 4]) -> [L; 4];
+}
+
+/// Folds `images`, each the size of `output`, into `output` with `kernel`,
+/// each image layered over the result of the ones before it. Rows are done in
+/// parallel.
+pub(crate) fn fold_images<K: PixelKernel>(
+    kernel: &K,
+    images: &[RgbaImage],
+    output: &mut RgbaImage,
+) -> Result<(), ImageDataErrors> {
+    check_sizes(images, output)?;
+    let row_len = output.width() as usize * 4;
+    if row_len == 0 || images.is_empty() {
+        return Ok(());
+    }
+
+    output
+        .par_chunks_mut(row_len)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let start = y * row_len;
+            let rows: Vec<&[u8]> = images
+                .iter()
+                .map(|image| &image.as_raw()[start..start + row_len])
+                .collect();
+            fold_row(kernel, &rows, row);
+        });
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+fn fold_row<K: PixelKernel>(kernel: &K, rows: &[&[u8]], out: &mut [u8]) {
+    if is_x86_feature_detected!("avx2") {
+        // SAFETY: the CPU supports AVX2.
+        unsafe { x86::fold_row_avx2(kernel, rows, out) }
+    } else {
+        x86::fold_row_sse2(kernel, rows, out)
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn fold_row<K: PixelKernel>(kernel: &K, rows: &[&[u8]], out: &mut [u8]) {
+    fold_lanes::<f32, K>(kernel, rows, out, 0);
+}
+
+/// Folds the pixels of `rows` into `out` from byte `from` on, as many whole
+/// groups of [`Lanes::PIXELS`] as fit, and returns where it stopped.
+#[inline(always)]
+fn fold_lanes<L: Lanes, K: PixelKernel>(
+    kernel: &K,
+    rows: &[&[u8]],
+    out: &mut [u8],
+    from: usize,
+) -> usize {
+    let step = L::PIXELS * 4;
+    let end = from + (out.len() - from) / step * step;
+    for i in (from..end).step_by(step) {
+        let mut pixels = L::load(&rows[0][i..i + step]);
+        for row in &rows[1..] {
+            pixels = kernel.apply(pixels, L::load(&row[i..i + step]));
+        }
+        L::store(pixels, &mut out[i..i + step]);
+    }
+    end
+}
+
+/// [`crate::select_channels`] for bytes, 16 or 32 at a time.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn select_channels(sources: &[&[u8]], start: usize, out: &mut [u8]) {
+    let done = if is_x86_feature_detected!("avx2") {
+        // SAFETY: the CPU supports AVX2.
+        unsafe { x86::select_channels_avx2(sources, start, out) }
+    } else {
+        x86::select_channels_sse2(sources, start, out)
+    };
+    crate::select_channels(sources, start + done, &mut out[done..]);
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub(crate) fn select_channels(sources: &[&[u8]], start: usize, out: &mut [u8]) {
+    crate::select_channels(sources, start, out);
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::{fold_lanes, Lanes, PixelKernel};
+    use std::arch::x86_64::*;
+    use std::ops::{Add, Div, Mul, Sub};
+
+    /// One channel of four pixels. SSE2 is part of x86-64, so this is
+    /// always available there.
+    #[derive(Clone, Copy)]
+    pub(super) struct F32x4(__m128);
+
+    macro_rules! impl_ops {
+        ($lanes:ident: $add:ident, $sub:ident, $mul:ident, $div:ident) => {
+            impl Add for $lanes {
+                type Output = Self;
+
+                #[inline(always)]
+                fn add(self, other: Self) -> Self {
+                    $lanes(unsafe { $add(self.0, other.0) })
+                }
+            }
+
+            impl Sub for $lanes {
+                type Output = Self;
+
+                #[inline(always)]
+                fn sub(self, other: Self) -> Self {
+                    $lanes(unsafe { $sub(self.0, other.0) })
+                }
+            }
+
+            impl Mul for $lanes {
+                type Output = Self;
+
+                #[inline(always)]
+                fn mul(self, other: Self) -> Self {
+                    $lanes(unsafe { $mul(self.0, other.0) })
+                }
+            }
+
+            impl Div for $lanes {
+                type Output = Self;
+
+                #[inline(always)]
+                fn div(self, other: Self) -> Self {
+                    $lanes(unsafe { $div(self.0, other.0) })
+                }
+            }
+        };
+    }
+
+    impl_ops!(F32x4: _mm_add_ps, _mm_sub_ps, _mm_mul_ps, _mm_div_ps);
+
+    impl Lanes for F32x4 {
+        type Mask = __m128;
+
+        const PIXELS: usize = 4;
+
+        #[inline(always)]
+        fn splat(value: f32) -> Self {
+            F32x4(unsafe { _mm_set1_ps(value) })
+        }
+
+        #[inline(always)]
+        fn min(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_min_ps(self.0, other.0) })
+        }
+
+        #[inline(always)]
+        fn max(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_max_ps(self.0, other.0) })
+        }
+
+        #[inline(always)]
+        fn clamp_unit(self) -> Self {
+            self.max(F32x4::splat(0.0)).min(F32x4::splat(1.0))
+        }
+
+        #[inline(always)]
+        fn abs(self) -> Self {
+            F32x4(unsafe { _mm_andnot_ps(_mm_set1_ps(-0.0), self.0) })
+        }
+
+        #[inline(always)]
+        fn sqrt(self) -> Self {
+            F32x4(unsafe { _mm_sqrt_ps(self.0) })
+        }
+
+        #[inline(always)]
+        fn le(self, other: Self) -> __m128 {
+            unsafe { _mm_cmple_ps(self.0, other.0) }
+        }
+
+        #[inline(always)]
+        fn ge(self, other: Self) -> __m128 {
+            unsafe { _mm_cmpge_ps(self.0, other.0) }
+        }
+
+        #[inline(always)]
+        fn gt(self, other: Self) -> __m128 {
+            unsafe { _mm_cmpgt_ps(self.0, other.0) }
+        }
+
+        #[inline(always)]
+        fn eq(self, other: Self) -> __m128 {
+            unsafe { _mm_cmpeq_ps(self.0, other.0) }
+        }
+
+        #[inline(always)]
+        fn select(mask: __m128, if_true: Self, if_false: Self) -> Self {
+            F32x4(unsafe {
+                _mm_or_ps(_mm_and_ps(mask, if_true.0), _mm_andnot_ps(mask, if_false.0))
+            })
+        }
+
+        #[inline(always)]
+        fn load(bytes: &[u8]) -> [Self; 4] {
+            assert!(bytes.len() >= 16);
+            unsafe {
+                let pixels = _mm_loadu_si128(bytes.as_ptr() as *const __m128i);
+                let byte = _mm_set1_epi32(0xff);
+                let scale = _mm_set1_ps(255.0);
+                let channels = [
+                    _mm_and_si128(pixels, byte),
+                    _mm_and_si128(_mm_srli_epi32::<8>(pixels), byte),
+                    _mm_and_si128(_mm_srli_epi32::<16>(pixels), byte),
+                    _mm_srli_epi32::<24>(pixels),
+                ];
+                channels.map(|channel| F32x4(_mm_div_ps(_mm_cvtepi32_ps(channel), scale)))
+            }
+        }
+
+        #[inline(always)]
+        fn store(channels: [Self; 4], bytes: &mut [u8]) {
+            assert!(bytes.len() >= 16);
+            unsafe {
+                let [r, g, b, a] = channels.map(round_sse2);
+                let pixels = _mm_or_si128(
+                    _mm_or_si128(r, _mm_slli_epi32::<8>(g)),
+                    _mm_or_si128(_mm_slli_epi32::<16>(b), _mm_slli_epi32::<24>(a)),
+                );
+                _mm_storeu_si128(bytes.as_mut_ptr() as *mut __m128i, pixels);
+            }
+        }
+    }
+
+    /// Scales to `0..=255` and rounds half away from zero, as `f32::round`
+    /// does; SSE2 itself only rounds half to even.
+    #[inline(always)]
+    fn round_sse2(channel: F32x4) -> __m128i {
+        unsafe {
+            let value = _mm_mul_ps(channel.clamp_unit().0, _mm_set1_ps(255.0));
+            let truncated = _mm_cvttps_epi32(value);
+            let fraction = _mm_sub_ps(value, _mm_cvtepi32_ps(truncated));
+            let round_up = _mm_castps_si128(_mm_cmpge_ps(fraction, _mm_set1_ps(0.5)));
+            _mm_sub_epi32(truncated, round_up)
+        }
+    }
+
+    pub(super) fn fold_row_sse2<K: PixelKernel>(kernel: &K, rows: &[&[u8]], out: &mut [u8]) {
+        let done = fold_lanes::<F32x4, K>(kernel, rows, out, 0);
+        fold_lanes::<f32, K>(kernel, rows, out, done);
+    }
+
+    pub(super) use avx2::fold_row_avx2;
+
+    /// Everything that works on AVX2 registers. [`F32x8`] does not leave this
+    /// module, and the only way in is [`fold_row_avx2`], whose caller
+    /// vouches for AVX2; that is what makes the safe [`Lanes`] methods of
+    /// `F32x8` sound.
+    mod avx2 {
+        use super::{fold_lanes, F32x4, Lanes, PixelKernel};
+        use std::arch::x86_64::*;
+        use std::ops::{Add, Div, Mul, Sub};
+
+        /// One channel of eight pixels.
+        #[derive(Clone, Copy)]
+        struct F32x8(__m256);
+
+        impl_ops!(F32x8: _mm256_add_ps, _mm256_sub_ps, _mm256_mul_ps, _mm256_div_ps);
+
+        impl Lanes for F32x8 {
+            type Mask = __m256;
+
+            const PIXELS: usize = 8;
+
+            #[inline(always)]
+            fn splat(value: f32) -> Self {
+                F32x8(unsafe { _mm256_set1_ps(value) })
+            }
+
+            #[inline(always)]
+            fn min(self, other: Self) -> Self {
+                F32x8(unsafe { _mm256_min_ps(self.0, other.0) })
+            }
+
+            #[inline(always)]
+            fn max(self, other: Self) -> Self {
+                F32x8(unsafe { _mm256_max_ps(self.0, other.0) })
+            }
+
+            #[inline(always)]
+            fn clamp_unit(self) -> Self {
+                self.max(F32x8::splat(0.0)).min(F32x8::splat(1.0))
+            }
+
+            #[inline(always)]
+            fn abs(self) -> Self {
+                F32x8(unsafe { _mm256_andnot_ps(_mm256_set1_ps(-0.0), self.0) })
+            }
+
+            #[inline(always)]
+            fn sqrt(self) -> Self {
+                F32x8(unsafe { _mm256_sqrt_ps(self.0) })
+            }
+
+            #[inline(always)]
+            fn le(self, other: Self) -> __m256 {
+                unsafe { _mm256_cmp_ps::<_CMP_LE_OQ>(self.0, other.0) }
+            }
+
+            #[inline(always)]
+            fn ge(self, other: Self) -> __m256 {
+                unsafe { _mm256_cmp_ps::<_CMP_GE_OQ>(self.0, other.0) }
+            }
+
+            #[inline(always)]
+            fn gt(self, other: Self) -> __m256 {
+                unsafe { _mm256_cmp_ps::<_CMP_GT_OQ>(self.0, other.0) }
+            }
+
+            #[inline(always)]
+            fn eq(self, other: Self) -> __m256 {
+                unsafe { _mm256_cmp_ps::<_CMP_EQ_OQ>(self.0, other.0) }
+            }
+
+            #[inline(always)]
+            fn select(mask: __m256, if_true: Self, if_false: Self) -> Self {
+                F32x8(unsafe { _mm256_blendv_ps(if_false.0, if_true.0, mask) })
+            }
+
+            #[inline(always)]
+            fn load(bytes: &[u8]) -> [Self; 4] {
+                assert!(bytes.len() >= 32);
+                unsafe {
+                    let pixels = _mm256_loadu_si256(bytes.as_ptr() as *const __m256i);
+                    let byte = _mm256_set1_epi32(0xff);
+                    let scale = _mm256_set1_ps(255.0);
+                    let r = _mm256_and_si256(pixels, byte);
+                    let g = _mm256_and_si256(_mm256_srli_epi32::<8>(pixels), byte);
+                    let b = _mm256_and_si256(_mm256_srli_epi32::<16>(pixels), byte);
+                    let a = _mm256_srli_epi32::<24>(pixels);
+                    [
+                        F32x8(_mm256_div_ps(_mm256_cvtepi32_ps(r), scale)),
+                        F32x8(_mm256_div_ps(_mm256_cvtepi32_ps(g), scale)),
+                        F32x8(_mm256_div_ps(_mm256_cvtepi32_ps(b), scale)),
+                        F32x8(_mm256_div_ps(_mm256_cvtepi32_ps(a), scale)),
+                    ]
+                }
+            }
+
+            #[inline(always)]
+            fn store(channels: [Self; 4], bytes: &mut [u8]) {
+                assert!(bytes.len() >= 32);
+                let [r, g, b, a] = channels;
+                let (r, g, b, a) = (round_avx2(r), round_avx2(g), round_avx2(b), round_avx2(a));
+                unsafe {
+                    let pixels = _mm256_or_si256(
+                        _mm256_or_si256(r, _mm256_slli_epi32::<8>(g)),
+                        _mm256_or_si256(_mm256_slli_epi32::<16>(b), _mm256_slli_epi32::<24>(a)),
+                    );
+                    _mm256_storeu_si256(bytes.as_mut_ptr() as *mut __m256i, pixels);
+                }
+            }
+        }
+
+        /// [`super::round_sse2`] for eight pixels.
+        #[inline(always)]
+        fn round_avx2(channel: F32x8) -> __m256i {
+            unsafe {
+                let value = _mm256_mul_ps(channel.clamp_unit().0, _mm256_set1_ps(255.0));
+                let truncated = _mm256_cvttps_epi32(value);
+                let fraction = _mm256_sub_ps(value, _mm256_cvtepi32_ps(truncated));
+                let round_up =
+                    _mm256_castps_si256(_mm256_cmp_ps::<_CMP_GE_OQ>(fraction, _mm256_set1_ps(0.5)));
+                _mm256_sub_epi32(truncated, round_up)
+            }
+        }
+
+        /// # Safety
+        ///
+        /// The CPU must support AVX2.
+        #[target_feature(enable = "avx2")]
+        pub(in super::super) unsafe fn fold_row_avx2<K: PixelKernel>(
+            kernel: &K,
+            rows: &[&[u8]],
+            out: &mut [u8],
+        ) {
+            let done = fold_lanes::<F32x8, K>(kernel, rows, out, 0);
+            let done = fold_lanes::<F32x4, K>(kernel, rows, out, done);
+            fold_lanes::<f32, K>(kernel, rows, out, done);
+        }
+    }
+
+    /// A mask over the byte of channel `c` in a 32-bit RGBA8 pixel.
+    fn channel_mask(c: usize) -> i32 {
+        (0xffu32 << (8 * c)) as i32
+    }
+
+    /// Selects channels 16 bytes at a time, returning how many bytes of `out`
+    /// were done.
+    pub(super) fn select_channels_sse2(sources: &[&[u8]], start: usize, out: &mut [u8]) -> usize {
+        let end = out.len() / 16 * 16;
+        for i in (0..end).step_by(16) {
+            let target = &mut out[i..i + 16];
+            unsafe {
+                let mut pixels = _mm_loadu_si128(target.as_ptr() as *const __m128i);
+                for (c, source) in sources.iter().enumerate() {
+                    let source = &source[start + i..start + i + 16];
+                    let mask = _mm_set1_epi32(channel_mask(c));
+                    let chosen = _mm_loadu_si128(source.as_ptr() as *const __m128i);
+                    pixels =
+                        _mm_or_si128(_mm_and_si128(mask, chosen), _mm_andnot_si128(mask, pixels));
+                }
+                _mm_storeu_si128(target.as_mut_ptr() as *mut __m128i, pixels);
+            }
+        }
+        end
+    }
+
+    /// Selects channels 32 bytes at a time, returning how many bytes of `out`
+    /// were done.
+    ///
+    /// # Safety
+    ///
+    /// The CPU must support AVX2.
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn select_channels_avx2(
+        sources: &[&[u8]],
+        start: usize,
+        out: &mut [u8],
+    ) -> usize {
+        let end = out.len() / 32 * 32;
+        for i in (0..end).step_by(32) {
+            let target = &mut out[i..i + 32];
+            let mut pixels = _mm256_loadu_si256(target.as_ptr() as *const __m256i);
+            for (c, source) in sources.iter().enumerate() {
+                let source = &source[start + i..start + i + 32];
+                let mask = _mm256_set1_epi32(channel_mask(c));
+                let chosen = _mm256_loadu_si256(source.as_ptr() as *const __m256i);
+                pixels = _mm256_blendv_epi8(pixels, chosen, mask);
+            }
+            _mm256_storeu_si256(target.as_mut_ptr() as *mut __m256i, pixels);
+        }
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Blend, BlendMode, Combiner, CompositeOperator};
+    use image::Rgba;
+
+    /// Rows of bytes that hit every alpha, including fully transparent and
+    /// fully opaque pixels.
+    fn rows(count: usize, width: usize) -> Vec<Vec<u8>> {
+        let mut state = 7u32;
+        (0..count)
+            .map(|_| {
+                (0..width * 4)
+                    .map(|i| {
+                        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                        match (i % 4, state >> 29) {
+                            (3, 0) => 0,
+                            (3, 1) => 255,
+                            _ => (state >> 16) as u8,
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Runs `kernel` over odd widths on every path and checks each writes
+    /// the bytes `combine_pixel` gives.
+    fn check<K: PixelKernel + Combiner>(kernel: &K, name: &str) {
+        for width in [1, 3, 5, 7, 9, 11, 13, 15, 17, 31, 33] {
+            let rows = rows(3, width);
+            let rows: Vec<&[u8]> = rows.iter().map(Vec::as_slice).collect();
+
+            let expected: Vec<u8> = (0..width * 4)
+                .step_by(4)
+                .flat_map(|i| {
+                    let pixels: Vec<_> = rows
+                        .iter()
+                        .map(|row| Rgba([0, 1, 2, 3].map(|c| row[i + c].to_unit())))
+                        .collect();
+                    kernel.combine_pixel(&pixels).0.map(u8::from_unit)
+                })
+                .collect();
+
+            let mut out = vec![0; width * 4];
+            fold_lanes::<f32, K>(kernel, &rows, &mut out, 0);
+            assert_eq!(out, expected, "scalar {} at width {}", name, width);
+
+            #[cfg(target_arch = "x86_64")]
+            {
+                let mut out = vec![0; width * 4];
+                x86::fold_row_sse2(kernel, &rows, &mut out);
+                assert_eq!(out, expected, "SSE2 {} at width {}", name, width);
+
+                if is_x86_feature_detected!("avx2") {
+                    let mut out = vec![0; width * 4];
+                    // SAFETY: the CPU supports AVX2.
+                    unsafe { x86::fold_row_avx2(kernel, &rows, &mut out) };
+                    assert_eq!(out, expected, "AVX2 {} at width {}", name, width);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_path_writes_the_same_bytes() {
+        for mode in BlendMode::ALL {
+            check(&Blend::new(mode, 1.0), mode.name());
+            check(&Blend::new(mode, 0.6), mode.name());
+        }
+        for operator in CompositeOperator::ALL {
+            check(&operator, operator.name());
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use crate::color::{linear_to_srgb, srgb_to_linear};
+use crate::combiner::Combiner;
+use crate::depth::BitDepth;
+use crate::error::ImageDataErrors;
+use crate::layout::ColorLayout;
+use crate::resize::SizeStrategy;
+use image::{DynamicImage, GenericImageView, Rgba};
+use std::str::FromStr;
+
+/// Rec. 601 luma weights, which the classic anaglyph methods are defined with.
+const LUMA: [f32; 3] = [0.299, 0.587, 0.114];
+
+/// The color filters of a pair of anaglyph glasses, left eye first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Glasses {
+    RedCyan,
+    GreenMagenta,
+    /// Amber and blue, as in ColorCode 3-D.
+    AmberBlue,
+}
+
+impl Glasses {
+    /// The output channels each eye's filter lets through, left then right.
+    fn channels(self) -> (&'static [usize], &'static [usize]) {
+        match self {
+            Glasses::RedCyan => (&[0], &[1, 2]),
+            Glasses::GreenMagenta => (&[1], &[0, 2]),
+            Glasses::AmberBlue => (&[0, 1], &[2]),
+        }
+    }
+}
+
+impl FromStr for Glasses {
+    type Err = ImageDataErrors;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec {
+            "red-cyan" => Ok(Glasses::RedCyan),
+            "green-magenta" => Ok(Glasses::GreenMagenta),
+            "amber-blue" => Ok(Glasses::AmberBlue),
+            _ => Err(ImageDataErrors::InvalidAnaglyph(spec.to_string())),
+        }
+    }
+}
+
+/// How the two views are mixed into the channels the glasses pass.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnaglyphMethod {
+    /// The luma of each view in a single channel: the left view's in the
+    /// first channel its filter passes, the right view's in the last. Dark,
+    /// but with the least ghosting.
+    True,
+    /// The luma of each view in every channel its filter passes.
+    Gray,
+    /// Each view's own channels where its filter passes them. Keeps the most
+    /// color, at the cost of retinal rivalry on saturated colors.
+    Color,
+    /// The left view in gray and the right in color, which tames the rivalry
+    /// of [`AnaglyphMethod::Color`] in the left eye.
+    HalfColor,
+    /// Eric Dubois' least-squares projections, fitted to the filters of
+    /// real glasses.
+    Dubois,
+}
+
+impl FromStr for AnaglyphMethod {
+    type Err = ImageDataErrors;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec {
+            "true" => Ok(AnaglyphMethod::True),
+            "gray" | "grey" => Ok(AnaglyphMethod::Gray),
+            "color" => Ok(AnaglyphMethod::Color),
+            "half-color" => Ok(AnaglyphMethod::HalfColor),
+            "dubois" | "optimized" => Ok(AnaglyphMethod::Dubois),
+            _ => Err(ImageDataErrors::InvalidAnaglyph(spec.to_string())),
+        }
+    }
+}
+
+/// A 3x3 color matrix, one row per output channel.
+type Matrix = [[f32; 3]; 3];
+
+/// Mixes a left and a right view into one image to be watched through
+/// anaglyph glasses.
+///
+/// Each output pixel is `left_matrix * left + right_matrix * right`, clamped,
+/// and as opaque as the more opaque of the two views.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Anaglyph {
+    pub glasses: Glasses,
+    pub method: AnaglyphMethod,
+    /// Mix in linear light instead of on sRGB encoded values. Dubois fitted
+    /// his matrices in linear light.
+    pub linear_light: bool,
+}
+
+impl Default for Anaglyph {
+    fn default() -> Self {
+        Anaglyph {
+            glasses: Glasses::RedCyan,
+            method: AnaglyphMethod::Dubois,
+            linear_light: false,
+        }
+    }
+}
+
+impl Anaglyph {
+    /// The matrices applied to the left and to the right view.
+    pub fn matrices(&self) -> (Matrix, Matrix) {
+        let (left, right) = self.glasses.channels();
+        let gray = |channels: &[usize]| rows(channels, |_| LUMA);
+        let color = |channels: &[usize]| {
+            rows(channels, |c| {
+                let mut row = [0.0; 3];
+                row[c] = 1.0;
+                row
+            })
+        };
+
+        match self.method {
+            AnaglyphMethod::True => (gray(&left[..1]), gray(&right[right.len() - 1..])),
+            AnaglyphMethod::Gray => (gray(left), gray(right)),
+            AnaglyphMethod::Color => (color(left), color(right)),
+            AnaglyphMethod::HalfColor => (gray(left), color(right)),
+            AnaglyphMethod::Dubois => dubois(self.glasses),
+        }
+    }
+}
+
+/// A matrix with `row(c)` for each channel `c` in `channels` and zeros
+/// elsewhere.
+fn rows(channels: &[usize], row: impl Fn(usize) -> [f32; 3]) -> Matrix {
+    let mut matrix = [[0.0; 3]; 3];
+    for &c in channels {
+        matrix[c] = row(c);
+    }
+    matrix
+}
+
+/// Dubois' matrices for each kind of glasses, as published on his site.
+fn dubois(glasses: Glasses) -> (Matrix, Matrix) {
+    match glasses {
+        Glasses::RedCyan => (
+            [
+                [0.437, 0.449, 0.164],
+                [-0.062, -0.062, -0.024],
+                [-0.048, -0.050, -0.017],
+            ],
+            [
+                [-0.011, -0.032, -0.007],
+                [0.377, 0.761, 0.009],
+                [-0.026, -0.093, 1.234],
+            ],
+        ),
+        Glasses::GreenMagenta => (
+            [
+                [-0.062, -0.158, -0.039],
+                [0.284, 0.668, 0.143],
+                [-0.015, -0.027, 0.021],
+            ],
+            [
+                [0.529, 0.705, 0.024],
+                [-0.016, -0.015, -0.065],
+                [0.009, 0.075, 0.937],
+            ],
+        ),
+        Glasses::AmberBlue => (
+            [
+                [1.062, -0.205, 0.299],
+                [-0.026, 0.908, 0.068],
+                [-0.038, -0.173, 0.022],
+            ],
+            [
+                [-0.016, -0.123, -0.017],
+                [0.006, 0.062, -0.017],
+                [0.094, 0.185, 0.911],
+            ],
+        ),
+    }
+}
+
+impl Combiner for Anaglyph {
+    /// Mixes `pixels[0]`, the left view, with `pixels[1]`, the right view.
+    fn combine_pixel(&self, pixels: &[Rgba<f32>]) -> Rgba<f32> {
+        let (left, right) = (pixels[0], pixels[pixels.len().min(2) - 1]);
+        let (left_matrix, right_matrix) = self.matrices();
+        let decode = |value: f32| {
+            if self.linear_light {
+                srgb_to_linear(value)
+            } else {
+                value
+            }
+        };
+        let left_rgb = [0, 1, 2].map(|c| decode(left[c]));
+        let right_rgb = [0, 1, 2].map(|c| decode(right[c]));
+
+        let mut out = [0.0; 4];
+        for c in 0..3 {
+            let mut value = 0.0;
+            for i in 0..3 {
+                value += left_matrix[c][i] * left_rgb[i] + right_matrix[c][i] * right_rgb[i];
+            }
+            out[c] = if self.linear_light {
+                linear_to_srgb(value)
+            } else {
+                value.clamp(0.0, 1.0)
+            };
+        }
+        out[3] = left[3].max(right[3]);
+        Rgba(out)
+    }
+
+    /// Always color; with alpha if either view has it.
+    fn output_layout(&self, inputs: &[ColorLayout]) -> ColorLayout {
+        ColorLayout {
+            color: true,
+            alpha: inputs.iter().any(|input| input.alpha),
+        }
+    }
+}
+
+/// Mixes `left` and `right` into an anaglyph at the given depth.
+///
+/// The right view is stretched to the size of the left one if they differ.
+/// A positive `parallax` moves the right view that many pixels to the right
+/// of the left one, pushing the scene further behind the screen; a negative
+/// one pulls it forward. Only the part covered by both views is kept.
+pub fn anaglyph(
+    left: DynamicImage,
+    right: DynamicImage,
+    anaglyph: &Anaglyph,
+    parallax: i32,
+    depth: BitDepth,
+) -> Result<DynamicImage, ImageDataErrors> {
+    let (width, height) = left.dimensions();
+    let right = if right.dimensions() == (width, height) {
+        right
+    } else {
+        SizeStrategy {
+            linear_light: anaglyph.linear_light,
+            ..SizeStrategy::default()
+        }
+        .resize(&right, (width, height))
+    };
+
+    let shift = parallax.unsigned_abs();
+    if shift >= width {
+        return Err(ImageDataErrors::ParallaxTooLarge(parallax, width));
+    }
+    let overlap = width - shift;
+    let (left, right) = match parallax {
+        0 => (left, right),
+        _ if parallax > 0 => (
+            left.crop_imm(shift, 0, overlap, height),
+            right.crop_imm(0, 0, overlap, height),
+        ),
+        _ => (
+            left.crop_imm(0, 0, overlap, height),
+            right.crop_imm(shift, 0, overlap, height),
+        ),
+    };
+
+    crate::combine_images_at(vec![left, right], anaglyph, depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+    fn view(pixel: Rgba<u8>) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, pixel))
+    }
+
+    fn mix(method: AnaglyphMethod, left: Rgba<u8>, right: Rgba<u8>) -> [u8; 4] {
+        let anaglyph = Anaglyph {
+            method,
+            ..Anaglyph::default()
+        };
+        let mixed = super::anaglyph(view(left), view(right), &anaglyph, 0, BitDepth::Eight);
+        mixed.unwrap().into_rgba8().get_pixel(1, 1).0
+    }
+
+    #[test]
+    fn red_cyan_shows_the_left_view_in_red_and_the_right_in_cyan() {
+        let methods = [
+            AnaglyphMethod::Gray,
+            AnaglyphMethod::Color,
+            AnaglyphMethod::HalfColor,
+            AnaglyphMethod::Dubois,
+        ];
+        for method in methods {
+            assert_eq!(mix(method, WHITE, BLACK), [255, 0, 0, 255], "{:?}", method);
+            assert_eq!(
+                mix(method, BLACK, WHITE),
+                [0, 255, 255, 255],
+                "{:?}",
+                method
+            );
+        }
+        // True anaglyphs put the right view in blue only.
+        assert_eq!(mix(AnaglyphMethod::True, WHITE, WHITE), [255, 0, 255, 255]);
+    }
+
+    #[test]
+    fn gray_methods_use_the_luma_of_each_view() {
+        let green = Rgba([0, 255, 0, 255]);
+        let luma = (0.587f32 * 255.0).round() as u8;
+        assert_eq!(
+            mix(AnaglyphMethod::Gray, green, green),
+            [luma, luma, luma, 255]
+        );
+        assert_eq!(
+            mix(AnaglyphMethod::HalfColor, green, green),
+            [luma, 255, 0, 255]
+        );
+        assert_eq!(mix(AnaglyphMethod::Color, green, green), [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn other_glasses_pass_their_own_channels() {
+        let color = |glasses| Anaglyph {
+            glasses,
+            method: AnaglyphMethod::Color,
+            linear_light: false,
+        };
+        let (left, right) = color(Glasses::GreenMagenta).matrices();
+        assert_eq!(left, [[0.0; 3], [0.0, 1.0, 0.0], [0.0; 3]]);
+        assert_eq!(right, [[1.0, 0.0, 0.0], [0.0; 3], [0.0, 0.0, 1.0]]);
+        let (left, right) = color(Glasses::AmberBlue).matrices();
+        assert_eq!(left, [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0; 3]]);
+        assert_eq!(right, [[0.0; 3], [0.0; 3], [0.0, 0.0, 1.0]]);
+    }
+
+    #[test]
+    fn parallax_shifts_the_right_view_and_keeps_the_overlap() {
+        // Column x is 60 * x bright in both views.
+        let ramp = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 1, |x, _| {
+            let value = x as u8 * 60;
+            Rgba([value, value, value, 255])
+        }));
+        let anaglyph = Anaglyph {
+            method: AnaglyphMethod::Color,
+            ..Anaglyph::default()
+        };
+        let mix = |parallax| {
+            super::anaglyph(
+                ramp.clone(),
+                ramp.clone(),
+                &anaglyph,
+                parallax,
+                BitDepth::Eight,
+            )
+            .map(|image| image.into_rgba8())
+        };
+
+        let behind = mix(1).unwrap();
+        assert_eq!(behind.dimensions(), (3, 1));
+        assert_eq!(behind.get_pixel(0, 0).0, [60, 0, 0, 255]);
+        assert_eq!(behind.get_pixel(2, 0).0, [180, 120, 120, 255]);
+
+        let forward = mix(-2).unwrap();
+        assert_eq!(forward.dimensions(), (2, 1));
+        assert_eq!(forward.get_pixel(0, 0).0, [0, 120, 120, 255]);
+
+        assert!(matches!(
+            mix(4),
+            Err(ImageDataErrors::ParallaxTooLarge(4, 4))
+        ));
+    }
+}
@@ -0,0 +1,946 @@
+use crate::combiner::Combiner;
+use crate::depth::BitDepth;
+use crate::encode::EncoderOptions;
+use crate::error::ImageDataErrors;
+use crate::layout::ColorLayout;
+use crate::STDIO_PATH;
+use deflate::{write::ZlibEncoder, CompressionOptions};
+use image::codecs::png::{CompressionType, FilterType};
+use image::error::{DecodingError, EncodingError, ImageFormatHint, LimitError, LimitErrorKind};
+use image::{io::Limits, io::Reader, DynamicImage, ImageBuffer, ImageError, ImageFormat};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, Write};
+use tiff::decoder::{ChunkType, Decoder, DecodingResult};
+use tiff::encoder::colortype::{self, ColorType};
+use tiff::encoder::{TiffEncoder, TiffKind, TiffValue};
+use tiff::tags::Tag;
+
+/// Channel values of whole rows, as the decoder produced them.
+enum Samples {
+    Eight(Vec<u8>),
+    Sixteen(Vec<u16>),
+}
+
+impl Samples {
+    fn len(&self) -> usize {
+        match self {
+            Samples::Eight(samples) => samples.len(),
+            Samples::Sixteen(samples) => samples.len(),
+        }
+    }
+
+    /// Appends `other`, which must have the same depth.
+    fn append(&mut self, other: Samples) {
+        match (self, other) {
+            (Samples::Eight(samples), Samples::Eight(other)) => samples.extend(other),
+            (Samples::Sixteen(samples), Samples::Sixteen(other)) => samples.extend(other),
+            _ => unreachable!("an image keeps its depth from chunk to chunk"),
+        }
+    }
+
+    /// Removes and returns the first `count` samples.
+    fn take_front(&mut self, count: usize) -> Samples {
+        match self {
+            Samples::Eight(samples) => Samples::Eight(samples.drain(..count).collect()),
+            Samples::Sixteen(samples) => Samples::Sixteen(samples.drain(..count).collect()),
+        }
+    }
+
+    /// Wraps `rows` rows of samples with `channels` channels as an image.
+    fn into_image(self, width: u32, rows: u32, channels: usize) -> DynamicImage {
+        let image = match (self, channels) {
+            (Samples::Eight(s), 1) => {
+                ImageBuffer::from_raw(width, rows, s).map(DynamicImage::ImageLuma8)
+            }
+            (Samples::Eight(s), 2) => {
+                ImageBuffer::from_raw(width, rows, s).map(DynamicImage::ImageLumaA8)
+            }
+            (Samples::Eight(s), 3) => {
+                ImageBuffer::from_raw(width, rows, s).map(DynamicImage::ImageRgb8)
+            }
+            (Samples::Eight(s), _) => {
+                ImageBuffer::from_raw(width, rows, s).map(DynamicImage::ImageRgba8)
+            }
+            (Samples::Sixteen(s), 1) => {
+                ImageBuffer::from_raw(width, rows, s).map(DynamicImage::ImageLuma16)
+            }
+            (Samples::Sixteen(s), 2) => {
+                ImageBuffer::from_raw(width, rows, s).map(DynamicImage::ImageLumaA16)
+            }
+            (Samples::Sixteen(s), 3) => {
+                ImageBuffer::from_raw(width, rows, s).map(DynamicImage::ImageRgb16)
+            }
+            (Samples::Sixteen(s), _) => {
+                ImageBuffer::from_raw(width, rows, s).map(DynamicImage::ImageRgba16)
+            }
+        };
+        image.expect("a band always holds whole rows")
+    }
+}
+
+/// Hands out an image's rows in whatever chunks its format stores them in.
+trait ChunkSource: Send {
+    /// Decodes the next chunk of whole rows, or `None` after the last one.
+    fn next_chunk(&mut self) -> Result<Option<Samples>, ImageError>;
+}
+
+/// A PNG image, read a row at a time.
+struct PngRows {
+    reader: png::Reader<BufReader<File>>,
+    sixteen: bool,
+}
+
+impl ChunkSource for PngRows {
+    fn next_chunk(&mut self) -> Result<Option<Samples>, ImageError> {
+        let row = match self.reader.next_row().map_err(png_decoding_error)? {
+            Some(row) => row.data(),
+            None => return Ok(None),
+        };
+        Ok(Some(if self.sixteen {
+            Samples::Sixteen(
+                row.chunks_exact(2)
+                    .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+                    .collect(),
+            )
+        } else {
+            Samples::Eight(row.to_vec())
+        }))
+    }
+}
+
+/// A TIFF image stored in strips, read a strip at a time.
+struct TiffStrips {
+    decoder: Decoder<BufReader<File>>,
+    strips_left: u32,
+}
+
+impl ChunkSource for TiffStrips {
+    fn next_chunk(&mut self) -> Result<Option<Samples>, ImageError> {
+        if self.strips_left == 0 {
+            return Ok(None);
+        }
+        self.strips_left -= 1;
+        let strip = self.decoder.read_strip().map_err(tiff_decoding_error)?;
+        tiff_samples(strip).map(Some)
+    }
+}
+
+/// A TIFF image stored in tiles, read a row of tiles at a time.
+struct TiffTiles {
+    decoder: Decoder<BufReader<File>>,
+    width: usize,
+    tile_width: usize,
+    channels: usize,
+    tile_rows_left: u32,
+}
+
+impl TiffTiles {
+    /// Interleaves the rows of tiles lying side by side into whole rows.
+    fn join<T: Copy>(&self, tiles: &[Vec<T>]) -> Vec<T> {
+        let rows = tiles[0].len() / (self.tile_width.min(self.width) * self.channels);
+        let mut joined = Vec::with_capacity(rows * self.width * self.channels);
+        for row in 0..rows {
+            for (i, tile) in tiles.iter().enumerate() {
+                let tile_width = self.tile_width.min(self.width - i * self.tile_width);
+                let row_len = tile_width * self.channels;
+                joined.extend_from_slice(&tile[row * row_len..(row + 1) * row_len]);
+            }
+        }
+        joined
+    }
+}
+
+impl ChunkSource for TiffTiles {
+    fn next_chunk(&mut self) -> Result<Option<Samples>, ImageError> {
+        if self.tile_rows_left == 0 {
+            return Ok(None);
+        }
+        self.tile_rows_left -= 1;
+
+        let tiles_across = self.width.div_ceil(self.tile_width);
+        let mut tiles = Vec::with_capacity(tiles_across);
+        for _ in 0..tiles_across {
+            let tile = self.decoder.read_tile().map_err(tiff_decoding_error)?;
+            tiles.push(tiff_samples(tile)?);
+        }
+
+        match &tiles[0] {
+            Samples::Eight(_) => {
+                let tiles: Vec<_> = tiles
+                    .into_iter()
+                    .filter_map(|tile| match tile {
+                        Samples::Eight(tile) => Some(tile),
+                        Samples::Sixteen(_) => None,
+                    })
+                    .collect();
+                Ok(Some(Samples::Eight(self.join(&tiles))))
+            }
+            Samples::Sixteen(_) => {
+                let tiles: Vec<_> = tiles
+                    .into_iter()
+                    .filter_map(|tile| match tile {
+                        Samples::Sixteen(tile) => Some(tile),
+                        Samples::Eight(_) => None,
+                    })
+                    .collect();
+                Ok(Some(Samples::Sixteen(self.join(&tiles))))
+            }
+        }
+    }
+}
+
+fn tiff_samples(result: DecodingResult) -> Result<Samples, ImageError> {
+    match result {
+        DecodingResult::U8(samples) => Ok(Samples::Eight(samples)),
+        DecodingResult::U16(samples) => Ok(Samples::Sixteen(samples)),
+        _ => Err(decoding_error(
+            ImageFormat::Tiff,
+            "unsupported sample format",
+        )),
+    }
+}
+
+/// One input, read a band of rows at a time.
+struct Input {
+    path: String,
+    format: ImageFormat,
+    width: u32,
+    height: u32,
+    channels: usize,
+    source: Box<dyn ChunkSource>,
+    /// Rows decoded but not yet handed out.
+    pending: Samples,
+}
+
+impl Input {
+    /// Opens the image at `path`, refusing it with
+    /// [`ImageDataErrors::ImageTooLarge`] if its size exceeds `limits` or
+    /// decoding a chunk of it would take more memory than they allow.
+    fn open(path: &str, limits: &Limits) -> Result<Input, ImageDataErrors> {
+        let unsupported = |reason: &str| {
+            ImageDataErrors::UnsupportedTiledInput(path.to_string(), reason.to_string())
+        };
+        if path == STDIO_PATH {
+            return Err(unsupported(
+                "standard input cannot be read a strip at a time",
+            ));
+        }
+
+        let read_error = |e| ImageDataErrors::UnableToReadImageFromPath(path.to_string(), e);
+        let format = Reader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(read_error)?
+            .format();
+        let file = BufReader::new(File::open(path).map_err(read_error)?);
+        let decode_error = |e| match e {
+            ImageError::Limits(_) => ImageDataErrors::ImageTooLarge(path.to_string()),
+            e => ImageDataErrors::UnableToDecodeImage(path.to_string(), e),
+        };
+        let max_alloc = limits.max_alloc.map_or(usize::MAX, |bytes| {
+            usize::try_from(bytes).unwrap_or(usize::MAX)
+        });
+
+        match format {
+            Some(ImageFormat::Png) => {
+                let mut decoder =
+                    png::Decoder::new_with_limits(file, png::Limits { bytes: max_alloc });
+                decoder.set_transformations(png::Transformations::EXPAND);
+                let reader = decoder
+                    .read_info()
+                    .map_err(|e| decode_error(png_decoding_error(e)))?;
+                if reader.info().interlaced {
+                    return Err(unsupported("interlaced PNG images are stored out of order"));
+                }
+                check_size(path, reader.info().width, reader.info().height, limits)?;
+                let (color, depth) = reader.output_color_type();
+                let sixteen = depth == png::BitDepth::Sixteen;
+                Ok(Input {
+                    path: path.to_string(),
+                    format: ImageFormat::Png,
+                    width: reader.info().width,
+                    height: reader.info().height,
+                    channels: color.samples(),
+                    source: Box::new(PngRows { reader, sixteen }),
+                    pending: empty_samples(sixteen),
+                })
+            }
+            Some(ImageFormat::Tiff) => {
+                let tiff_error = |e| decode_error(tiff_decoding_error(e));
+                let mut tiff_limits = tiff::decoder::Limits::default();
+                tiff_limits.decoding_buffer_size = max_alloc;
+                tiff_limits.intermediate_buffer_size = max_alloc;
+                let mut decoder = Decoder::new(file)
+                    .map_err(tiff_error)?
+                    .with_limits(tiff_limits);
+                let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+                check_size(path, width, height, limits)?;
+                let (channels, bits) = match decoder.colortype().map_err(tiff_error)? {
+                    tiff::ColorType::Gray(bits) => (1, bits),
+                    tiff::ColorType::GrayA(bits) => (2, bits),
+                    tiff::ColorType::RGB(bits) => (3, bits),
+                    tiff::ColorType::RGBA(bits) => (4, bits),
+                    other => {
+                        return Err(unsupported(&format!(
+                            "{:?} TIFF images are not supported",
+                            other
+                        )))
+                    }
+                };
+                if bits != 8 && bits != 16 {
+                    return Err(unsupported(&format!(
+                        "{}-bit TIFF images are not supported",
+                        bits
+                    )));
+                }
+
+                let source: Box<dyn ChunkSource> = match decoder.get_chunk_type() {
+                    ChunkType::Strip => {
+                        let strips_left = decoder.strip_count().map_err(tiff_error)?;
+                        Box::new(TiffStrips {
+                            decoder,
+                            strips_left,
+                        })
+                    }
+                    ChunkType::Tile => {
+                        let tile_width = decoder.get_tag_u32(Tag::TileWidth).map_err(tiff_error)?;
+                        let tile_length =
+                            decoder.get_tag_u32(Tag::TileLength).map_err(tiff_error)?;
+                        Box::new(TiffTiles {
+                            decoder,
+                            width: width as usize,
+                            tile_width: tile_width as usize,
+                            channels,
+                            tile_rows_left: height.div_ceil(tile_length),
+                        })
+                    }
+                };
+                Ok(Input {
+                    path: path.to_string(),
+                    format: ImageFormat::Tiff,
+                    width,
+                    height,
+                    channels,
+                    source,
+                    pending: empty_samples(bits == 16),
+                })
+            }
+            Some(format) => Err(unsupported(&format!(
+                "{:?} images cannot be read a strip at a time",
+                format
+            ))),
+            None => Err(ImageDataErrors::UnableToFormatImage(path.to_string())),
+        }
+    }
+
+    fn depth(&self) -> BitDepth {
+        match self.pending {
+            Samples::Eight(_) => BitDepth::Eight,
+            Samples::Sixteen(_) => BitDepth::Sixteen,
+        }
+    }
+
+    fn layout(&self) -> ColorLayout {
+        ColorLayout {
+            color: self.channels >= 3,
+            alpha: matches!(self.channels, 2 | 4),
+        }
+    }
+
+    /// Decodes the next `rows` rows.
+    fn read_rows(&mut self, rows: u32) -> Result<DynamicImage, ImageDataErrors> {
+        let wanted = rows as usize * self.width as usize * self.channels;
+        let format = self.format;
+        while self.pending.len() < wanted {
+            let chunk = self
+                .source
+                .next_chunk()
+                .and_then(|chunk| chunk.ok_or_else(|| decoding_error(format, "image ended early")))
+                .map_err(|e| match e {
+                    ImageError::Limits(_) => ImageDataErrors::ImageTooLarge(self.path.clone()),
+                    e => ImageDataErrors::UnableToDecodeImage(self.path.clone(), e),
+                })?;
+            self.pending.append(chunk);
+        }
+        let samples = self.pending.take_front(wanted);
+        Ok(samples.into_image(self.width, rows, self.channels))
+    }
+}
+
+fn empty_samples(sixteen: bool) -> Samples {
+    if sixteen {
+        Samples::Sixteen(Vec::new())
+    } else {
+        Samples::Eight(Vec::new())
+    }
+}
+
+/// Combines the inputs a band of rows at a time.
+struct Bands<'a> {
+    inputs: Vec<Input>,
+    combiner: &'a dyn Combiner,
+    depth: BitDepth,
+    layout: ColorLayout,
+    output: &'a str,
+    strip_rows: u32,
+    next_row: u32,
+}
+
+impl Bands<'_> {
+    fn width(&self) -> u32 {
+        self.inputs[0].width
+    }
+
+    fn height(&self) -> u32 {
+        self.inputs[0].height
+    }
+
+    /// Combines the next band, or returns `None` once every row is done.
+    fn next_band(&mut self) -> Result<Option<DynamicImage>, ImageDataErrors> {
+        let height = self.height();
+        if self.next_row >= height {
+            return Ok(None);
+        }
+        let rows = self.strip_rows.min(height - self.next_row);
+        self.next_row += rows;
+
+        // Decode every input's rows at once, reporting the first failure in
+        // input order.
+        let band: Vec<_> = self
+            .inputs
+            .par_iter_mut()
+            .map(|input| input.read_rows(rows))
+            .collect();
+        let band = band.into_iter().collect::<Result<Vec<_>, _>>()?;
+        let combined = crate::combine_images_at(band, self.combiner, self.depth)?;
+        Ok(Some(self.layout.apply(combined)))
+    }
+}
+
+/// Combines the images at `paths` into `output` a strip of `strip_rows` rows
+/// at a time, so only a few rows of every image are held in memory.
+///
+/// The inputs must be PNG or TIFF images of the same size, as nothing is
+/// resized. The output must be PNG or TIFF too; PNG can also be written to
+/// standard output. Depth and channels are chosen as for an in-memory
+/// combine. Inputs larger than `limits` allow, or whose chunks take more
+/// memory to decode, fail with [`ImageDataErrors::ImageTooLarge`].
+pub fn combine_tiled(
+    paths: &[String],
+    output: &str,
+    format: ImageFormat,
+    combiner: &dyn Combiner,
+    options: &EncoderOptions,
+    limits: &Limits,
+    strip_rows: u32,
+) -> Result<(), ImageDataErrors> {
+    let unsupported = |reason: &str| {
+        Err(ImageDataErrors::UnsupportedTiledInput(
+            output.to_string(),
+            reason.to_string(),
+        ))
+    };
+    match format {
+        ImageFormat::Png => {}
+        ImageFormat::Tiff if output == STDIO_PATH => {
+            return unsupported("TIFF cannot be written to standard output")
+        }
+        ImageFormat::Tiff => {}
+        _ => {
+            return unsupported(&format!(
+                "only PNG and TIFF can be written, not {:?}",
+                format
+            ))
+        }
+    }
+
+    let mut inputs = Vec::with_capacity(paths.len());
+    for path in paths {
+        inputs.push(Input::open(path, limits)?);
+    }
+    let first = inputs.first().ok_or(ImageDataErrors::NoInputImages)?;
+    let (width, height) = (first.width, first.height);
+    if let Some(input) = inputs
+        .iter()
+        .find(|input| (input.width, input.height) != (width, height))
+    {
+        return Err(ImageDataErrors::UnsupportedTiledInput(
+            input.path.clone(),
+            format!(
+                "it is {}x{} but the first input is {}x{}",
+                input.width, input.height, width, height
+            ),
+        ));
+    }
+
+    let layouts: Vec<_> = inputs.iter().map(Input::layout).collect();
+    let layout = combiner.output_layout(&layouts).for_format(format);
+    let depth = inputs
+        .iter()
+        .map(Input::depth)
+        .max()
+        .unwrap_or(BitDepth::Eight)
+        .for_format(format, layout);
+    let mut bands = Bands {
+        inputs,
+        combiner,
+        depth,
+        layout,
+        output,
+        strip_rows: strip_rows.max(1),
+        next_row: 0,
+    };
+
+    if output == STDIO_PATH {
+        return write_png(&mut bands, std::io::stdout().lock(), options);
+    }
+    let file = File::create(output).map_err(|e| {
+        ImageDataErrors::UnableToSaveImage(output.to_string(), ImageError::IoError(e))
+    })?;
+    let result = match format {
+        ImageFormat::Png => write_png(&mut bands, BufWriter::new(file), options),
+        _ => write_tiff(&mut bands, BufWriter::new(file)),
+    };
+    if result.is_err() {
+        // Do not leave half an image behind.
+        let _ = std::fs::remove_file(output);
+    }
+    result
+}
+
+fn write_png<W: Write + 'static>(
+    bands: &mut Bands,
+    writer: W,
+    options: &EncoderOptions,
+) -> Result<(), ImageDataErrors> {
+    let output = bands.output;
+    let save_error = |e| encoding_error(output, ImageFormat::Png, e);
+    let first = match bands.next_band()? {
+        Some(band) => band,
+        None => return Ok(()),
+    };
+
+    let mut encoder = png::Encoder::new(writer, bands.width(), bands.height());
+    let color = first.color();
+    encoder.set_color(match (color.has_color(), color.has_alpha()) {
+        (false, false) => png::ColorType::Grayscale,
+        (false, true) => png::ColorType::GrayscaleAlpha,
+        (true, false) => png::ColorType::Rgb,
+        (true, true) => png::ColorType::Rgba,
+    });
+    let sixteen = BitDepth::of(&first) == BitDepth::Sixteen;
+    encoder.set_depth(if sixteen {
+        png::BitDepth::Sixteen
+    } else {
+        png::BitDepth::Eight
+    });
+    let compression = match options.png_compression {
+        CompressionType::Fast => CompressionOptions::fast(),
+        CompressionType::Best => CompressionOptions::high(),
+        CompressionType::Huffman => CompressionOptions::huffman_only(),
+        CompressionType::Rle => CompressionOptions::rle(),
+        _ => CompressionOptions::default(),
+    };
+    let filter = match options.png_filter {
+        FilterType::NoFilter => Some(png::FilterType::NoFilter),
+        FilterType::Sub => Some(png::FilterType::Sub),
+        FilterType::Up => Some(png::FilterType::Up),
+        FilterType::Avg => Some(png::FilterType::Avg),
+        FilterType::Paeth => Some(png::FilterType::Paeth),
+        _ => None,
+    };
+
+    // The png crate's stream writer filters each row against the previous
+    // row as filtered rather than as it was, which corrupts every filter but
+    // none and sub, so rows are filtered and compressed here instead.
+    let mut writer = encoder.write_header().map_err(save_error)?;
+    let bytes_per_pixel = color.bytes_per_pixel() as usize;
+    let mut rows = RowFilter::new(filter, bytes_per_pixel, bands.width() as usize);
+    let mut zlib = ZlibEncoder::new(IdatChunks::new(&mut writer), compression);
+    let mut band = Some(first);
+    while let Some(image) = band {
+        let bytes = image.as_bytes();
+        let swapped: Vec<u8>;
+        let bytes = if sixteen {
+            // PNG stores 16-bit samples big endian.
+            swapped = bytes
+                .chunks_exact(2)
+                .flat_map(|pair| u16::from_ne_bytes([pair[0], pair[1]]).to_be_bytes())
+                .collect();
+            &swapped
+        } else {
+            bytes
+        };
+        for row in bytes.chunks_exact(rows.row_len()) {
+            zlib.write_all(rows.filter(row))
+                .map_err(|e| save_error(png::EncodingError::IoError(e)))?;
+        }
+        band = bands.next_band()?;
+    }
+    zlib.finish()
+        .and_then(IdatChunks::finish)
+        .map_err(|e| save_error(png::EncodingError::IoError(e)))?;
+    writer.finish().map_err(save_error)
+}
+
+/// Applies a PNG filter to one row after another.
+struct RowFilter {
+    /// The filter for every row, or `None` to pick one per row.
+    filter: Option<png::FilterType>,
+    bytes_per_pixel: usize,
+    /// The previous row, unfiltered.
+    previous: Vec<u8>,
+    /// The filter type byte followed by the filtered row.
+    filtered: Vec<u8>,
+    /// Where rows are filtered when trying out filters.
+    scratch: Vec<u8>,
+}
+
+impl RowFilter {
+    fn new(filter: Option<png::FilterType>, bytes_per_pixel: usize, width: usize) -> Self {
+        let row_len = bytes_per_pixel * width;
+        RowFilter {
+            filter,
+            bytes_per_pixel,
+            previous: vec![0; row_len],
+            filtered: vec![0; row_len + 1],
+            scratch: vec![0; row_len + 1],
+        }
+    }
+
+    fn row_len(&self) -> usize {
+        self.previous.len()
+    }
+
+    /// Filters `row`, returning it prefixed with its filter type.
+    fn filter(&mut self, row: &[u8]) -> &[u8] {
+        match self.filter {
+            Some(filter) => self.apply(filter, row),
+            None => {
+                // Pick the filter whose output has the smallest sum of
+                // absolute values as signed bytes, as libpng does.
+                let mut best = u64::MAX;
+                for filter in [
+                    png::FilterType::NoFilter,
+                    png::FilterType::Sub,
+                    png::FilterType::Up,
+                    png::FilterType::Avg,
+                    png::FilterType::Paeth,
+                ] {
+                    std::mem::swap(&mut self.filtered, &mut self.scratch);
+                    self.apply(filter, row);
+                    let sum = self.filtered[1..]
+                        .iter()
+                        .map(|&byte| u64::from((byte as i8).unsigned_abs()))
+                        .sum();
+                    if sum < best {
+                        best = sum;
+                    } else {
+                        std::mem::swap(&mut self.filtered, &mut self.scratch);
+                    }
+                }
+            }
+        }
+        self.previous.copy_from_slice(row);
+        &self.filtered
+    }
+
+    /// Filters `row` with `filter` into `filtered`.
+    fn apply(&mut self, filter: png::FilterType, row: &[u8]) {
+        let bpp = self.bytes_per_pixel;
+        self.filtered[0] = filter as u8;
+        let (up, out) = (&self.previous, &mut self.filtered[1..]);
+        for i in 0..row.len() {
+            let left = if i >= bpp { row[i - bpp] } else { 0 };
+            let up_left = if i >= bpp { up[i - bpp] } else { 0 };
+            out[i] = row[i].wrapping_sub(match filter {
+                png::FilterType::NoFilter => 0,
+                png::FilterType::Sub => left,
+                png::FilterType::Up => up[i],
+                png::FilterType::Avg => ((u16::from(left) + u16::from(up[i])) / 2) as u8,
+                png::FilterType::Paeth => paeth(left, up[i], up_left),
+            });
+        }
+    }
+}
+
+/// The Paeth predictor: whichever of the left, upper and upper left bytes is
+/// closest to `left + up - up_left`.
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = i16::from(left) + i16::from(up) - i16::from(up_left);
+    let distance = |byte: u8| (estimate - i16::from(byte)).abs();
+    if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+        left
+    } else if distance(up) <= distance(up_left) {
+        up
+    } else {
+        up_left
+    }
+}
+
+/// Cuts compressed image data into IDAT chunks.
+struct IdatChunks<'a, W: Write> {
+    writer: &'a mut png::Writer<W>,
+    buffer: Vec<u8>,
+}
+
+impl<'a, W: Write> IdatChunks<'a, W> {
+    /// The most data put in one chunk.
+    const CHUNK_LEN: usize = 1 << 20;
+
+    fn new(writer: &'a mut png::Writer<W>) -> Self {
+        IdatChunks {
+            writer,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn write_chunk(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.writer.write_chunk(png::chunk::IDAT, &self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Writes out whatever is left.
+    fn finish(mut self) -> std::io::Result<()> {
+        self.write_chunk()
+    }
+}
+
+impl<W: Write> Write for IdatChunks<'_, W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let taken = data.len().min(Self::CHUNK_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..taken]);
+        if self.buffer.len() == Self::CHUNK_LEN {
+            self.write_chunk()?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_tiff<W: Write + Seek>(bands: &mut Bands, writer: W) -> Result<(), ImageDataErrors> {
+    let output = bands.output;
+    let save_error = |e| encoding_error(output, ImageFormat::Tiff, e);
+    let first = match bands.next_band()? {
+        Some(band) => band,
+        None => return Ok(()),
+    };
+
+    // Classic TIFF uses 32-bit offsets, so large outputs need BigTIFF.
+    let size = u64::from(bands.width())
+        * u64::from(bands.height())
+        * u64::from(first.color().bytes_per_pixel());
+    if size < u64::from(u32::MAX) / 2 {
+        let mut encoder = TiffEncoder::new(writer).map_err(save_error)?;
+        write_tiff_image(&mut encoder, bands, first)
+    } else {
+        let mut encoder = TiffEncoder::new_big(writer).map_err(save_error)?;
+        write_tiff_image(&mut encoder, bands, first)
+    }
+}
+
+fn write_tiff_image<W: Write + Seek, K: TiffKind>(
+    encoder: &mut TiffEncoder<W, K>,
+    bands: &mut Bands,
+    first: DynamicImage,
+) -> Result<(), ImageDataErrors> {
+    match first.color() {
+        image::ColorType::L8 => {
+            write_tiff_strips::<_, colortype::Gray8, _>(encoder, bands, first, |band| {
+                band.as_luma8().map(|band| band.as_raw().as_slice())
+            })
+        }
+        image::ColorType::Rgb8 => {
+            write_tiff_strips::<_, colortype::RGB8, _>(encoder, bands, first, |band| {
+                band.as_rgb8().map(|band| band.as_raw().as_slice())
+            })
+        }
+        image::ColorType::L16 => {
+            write_tiff_strips::<_, colortype::Gray16, _>(encoder, bands, first, |band| {
+                band.as_luma16().map(|band| band.as_raw().as_slice())
+            })
+        }
+        image::ColorType::Rgb16 => {
+            write_tiff_strips::<_, colortype::RGB16, _>(encoder, bands, first, |band| {
+                band.as_rgb16().map(|band| band.as_raw().as_slice())
+            })
+        }
+        image::ColorType::Rgba16 => {
+            write_tiff_strips::<_, colortype::RGBA16, _>(encoder, bands, first, |band| {
+                band.as_rgba16().map(|band| band.as_raw().as_slice())
+            })
+        }
+        _ => write_tiff_strips::<_, colortype::RGBA8, _>(encoder, bands, first, |band| {
+            band.as_rgba8().map(|band| band.as_raw().as_slice())
+        }),
+    }
+}
+
+fn write_tiff_strips<W, C, K>(
+    encoder: &mut TiffEncoder<W, K>,
+    bands: &mut Bands,
+    first: DynamicImage,
+    samples: fn(&DynamicImage) -> Option<&[C::Inner]>,
+) -> Result<(), ImageDataErrors>
+where
+    W: Write + Seek,
+    C: ColorType,
+    K: TiffKind,
+    [C::Inner]: TiffValue,
+{
+    let output = bands.output;
+    let save_error = |e| encoding_error(output, ImageFormat::Tiff, e);
+    let mut image = encoder
+        .new_image::<C>(bands.width(), bands.height())
+        .map_err(save_error)?;
+    image.rows_per_strip(bands.strip_rows).map_err(save_error)?;
+
+    let mut band = Some(first);
+    while let Some(strip) = band {
+        let strip = samples(&strip).expect("every band has the color type of the first");
+        image.write_strip(strip).map_err(save_error)?;
+        band = bands.next_band()?;
+    }
+    image.finish().map_err(save_error)
+}
+
+fn decoding_error(
+    format: ImageFormat,
+    e: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(format), e))
+}
+
+/// Fails with [`ImageDataErrors::ImageTooLarge`] if an image of `width` by
+/// `height` exceeds the size `limits` allow.
+fn check_size(path: &str, width: u32, height: u32, limits: &Limits) -> Result<(), ImageDataErrors> {
+    let too_wide = limits.max_image_width.is_some_and(|max| width > max);
+    let too_tall = limits.max_image_height.is_some_and(|max| height > max);
+    if too_wide || too_tall {
+        return Err(ImageDataErrors::ImageTooLarge(path.to_string()));
+    }
+    Ok(())
+}
+
+fn png_decoding_error(e: png::DecodingError) -> ImageError {
+    match e {
+        png::DecodingError::IoError(e) => ImageError::IoError(e),
+        png::DecodingError::LimitsExceeded => insufficient_memory(),
+        e => decoding_error(ImageFormat::Png, e),
+    }
+}
+
+fn tiff_decoding_error(e: tiff::TiffError) -> ImageError {
+    match e {
+        tiff::TiffError::IoError(e) => ImageError::IoError(e),
+        tiff::TiffError::LimitsExceeded => insufficient_memory(),
+        e => decoding_error(ImageFormat::Tiff, e),
+    }
+}
+
+fn insufficient_memory() -> ImageError {
+    ImageError::Limits(LimitError::from_kind(LimitErrorKind::InsufficientMemory))
+}
+
+fn encoding_error(
+    output: &str,
+    format: ImageFormat,
+    e: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> ImageDataErrors {
+    ImageDataErrors::UnableToSaveImage(
+        output.to_string(),
+        ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(format), e)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{combine_images_at, parse_png_filter, Blend, BlendMode};
+    use image::Rgba;
+
+    /// A noisy image, so every filter has something to do.
+    fn noise(width: u32, height: u32, seed: u32) -> ImageBuffer<Rgba<u16>, Vec<u16>> {
+        let mut state = seed;
+        ImageBuffer::from_fn(width, height, |x, y| {
+            let mut next = || {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 8) as u16
+            };
+            let base = ((x * 1500 + y * 900) % 65536) as u16;
+            Rgba([base, next(), (base / 2) ^ (next() / 8), 65535 - next() / 4])
+        })
+    }
+
+    #[test]
+    fn strips_decode_like_an_in_memory_combine_with_every_png_filter() {
+        let dir = std::env::temp_dir().join(format!("tiled-png-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let combiner = Blend::new(BlendMode::Multiply, 1.0);
+
+        for sixteen in [false, true] {
+            let images: Vec<_> = (0..2)
+                .map(|seed| {
+                    let image = DynamicImage::ImageRgba16(noise(37, 29, seed));
+                    if sixteen {
+                        image
+                    } else {
+                        DynamicImage::ImageRgba8(image.to_rgba8())
+                    }
+                })
+                .collect();
+            let paths: Vec<_> = images
+                .iter()
+                .enumerate()
+                .map(|(index, image)| {
+                    let path = dir.join(format!("input-{}-{}.png", sixteen, index));
+                    image.save(&path).unwrap();
+                    path.to_str().unwrap().to_string()
+                })
+                .collect();
+
+            let layouts: Vec<_> = images.iter().map(ColorLayout::of).collect();
+            let layout = combiner
+                .output_layout(&layouts)
+                .for_format(ImageFormat::Png);
+            let depth = BitDepth::highest(&images).for_format(ImageFormat::Png, layout);
+            let expected = layout.apply(combine_images_at(images, &combiner, depth).unwrap());
+
+            for filter in ["none", "sub", "up", "avg", "paeth", "adaptive"] {
+                let options = EncoderOptions {
+                    png_filter: parse_png_filter(filter).unwrap(),
+                    ..EncoderOptions::default()
+                };
+                let output = dir.join(format!("output-{}-{}.png", sixteen, filter));
+                let output = output.to_str().unwrap();
+                combine_tiled(
+                    &paths,
+                    output,
+                    ImageFormat::Png,
+                    &combiner,
+                    &options,
+                    &Limits::default(),
+                    7,
+                )
+                .unwrap();
+
+                let decoded = image::open(output).unwrap();
+                assert_eq!(decoded.color(), expected.color(), "--png-filter {}", filter);
+                assert!(
+                    decoded.as_bytes() == expected.as_bytes(),
+                    "--png-filter {} changed the pixels of a {}-bit combine",
+                    filter,
+                    if sixteen { 16 } else { 8 }
+                );
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}